@@ -1,27 +1,53 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use chrono::NaiveDate;
-use rust_decimal::Decimal;
+use regex::{Captures, Regex};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
 
-use crate::utils::{escape_string, last_component, naive_today};
+use crate::utils::{
+    command_split, escape_string, has_root, last_component, ledger_today, parse_date,
+};
+use crate::{
+    Beancount, ExpenseSign, Granularity, MatchMode, PostingOrder, Rounding, Shortcut, TagSpaces,
+};
+
+/// Flag of a complete transaction
+pub const FLAG_COMPLETE: char = '*';
+/// Flag of a transaction that needs to be reviewed
+pub const FLAG_INCOMPLETE: char = '!';
 
 #[derive(Debug)]
 pub struct Transaction<'ac, 'am> {
     date: NaiveDate,
+    flag: char,
     payee: Option<String>,
     narration: String,
     tags: Vec<String>,
+    metadata: Vec<(String, String)>,
     postings: Vec<Posting<'ac, 'am>>,
+    /// Notes shown under the preview but not written to the ledger
+    notes: Vec<String>,
+    /// Whether the amounts of the postings are aligned in a column
+    align_amounts: bool,
+    /// Whether the `txn` keyword is written in place of the `*` flag
+    txn_keyword: bool,
+    posting_order: PostingOrder,
 }
 
 #[derive(Debug)]
 pub struct Posting<'ac, 'am> {
     account: &'ac str,
     amount: Amount<'am>,
+    /// Per-unit price of the amount
+    price: Option<Amount<'am>>,
 }
 
 #[derive(Debug, Clone)]
@@ -38,45 +64,65 @@ fn account_matches(account: &str, term: &str) -> bool {
         .all(|t| loweraccount.contains(t))
 }
 
+/// Determines whether each subterm in the lowercased search term `term` is the beginning of a
+/// component of `account`
+fn account_matches_prefix(account: &str, term: &str, sep: &str) -> bool {
+    let loweraccount = account.to_lowercase();
+    term.split_ascii_whitespace()
+        .all(|t| loweraccount.split(sep).any(|c| c.starts_with(t)))
+}
+
+/// Finds the account matching `term`. The returned boolean is true if the full account name match
+/// is ambiguous, and the account is picked by the less reliable last component matches, which
+/// only happens in [`MatchMode::Smart`].
 fn filter_account<'a>(
     accounts: &'a [String],
     term: &str,
+    sep: &str,
+    mode: MatchMode,
     pred: impl Fn(&&String) -> bool,
-) -> Result<&'a String> {
+) -> Result<(&'a String, bool)> {
     let term = term.to_lowercase();
     // full account name match
     let matched: Vec<_> = accounts
         .iter()
-        .filter(|ac| account_matches(ac, &term) && pred(ac))
+        .filter(|ac| {
+            let matches = match mode {
+                MatchMode::Smart | MatchMode::Strict => account_matches(ac, &term),
+                MatchMode::Prefix => account_matches_prefix(ac, &term, sep),
+            };
+            matches && pred(ac)
+        })
         .collect();
     match matched.len() {
         0 => bail!("No matched account"),
-        1 => return Ok(matched[0]),
+        1 => return Ok((matched[0], false)),
+        _ if mode != MatchMode::Smart => bail!("More than one matched account: {:?}", matched),
         _ => {}
     }
 
     // last component match
     let last_match: Vec<_> = matched
         .iter()
-        .filter(|ac| account_matches(last_component(ac), &term))
+        .filter(|ac| account_matches(last_component(ac, sep), &term))
         .collect();
     match last_match.len() {
         0 => bail!("More than one matched account: {:?}", matched),
-        1 => return Ok(last_match[0]),
+        1 => return Ok((last_match[0], true)),
         _ => {}
     }
 
     // last component exact match
     let last_exact_match: Vec<_> = matched
         .iter()
-        .filter(|ac| last_component(ac).to_lowercase() == term)
+        .filter(|ac| last_component(ac, sep).to_lowercase() == term)
         .collect();
     match last_exact_match.len() {
         0 => bail!(
             "More than one last-component matched account: {:?}",
             last_match
         ),
-        1 => Ok(last_exact_match[0]),
+        1 => Ok((last_exact_match[0], true)),
         _ => bail!(
             "More than one last-component exact-match account: {:?}",
             last_exact_match
@@ -84,236 +130,3231 @@ fn filter_account<'a>(
     }
 }
 
+/// Returns the accounts matching `term` in full account names
+pub fn find_accounts<'a>(accounts: &'a [String], term: &str) -> Vec<&'a String> {
+    let term = term.to_lowercase();
+    accounts
+        .iter()
+        .filter(|ac| account_matches(ac, &term))
+        .collect()
+}
+
+/// Information about the message a transaction is parsed from
+#[derive(Debug, Clone, Copy)]
+pub struct ParseContext<'a> {
+    /// Currency used when the currency is omitted
+    pub default_currency: &'a str,
+    /// Payee used when the payee is omitted
+    pub default_payee: Option<&'a str>,
+    /// Name of the user entering the transaction
+    pub user: Option<&'a str>,
+}
+
+impl<'a> ParseContext<'a> {
+    pub fn new(default_currency: &'a str) -> Self {
+        Self {
+            default_currency,
+            default_payee: None,
+            user: None,
+        }
+    }
+}
+
 impl<'ac, 'am: 'ac> Transaction<'ac, 'am> {
     /// Parses a transaction from a command.
-    /// [>Payee] [#Tag ...] Amount Account ExpAccount Narration
+    /// [Date] [>Payee] [#Tag ...] Amount Account ExpAccount Narration
+    /// [Date] [>Payee] [#Tag ...] Amount Account (Amount|_) ExpAccount ... Narration
+    /// [Date] [>Payee] [#Tag ...] < Amount IncomeAccount Account Narration
+    /// [Date] [>Payee] [#Tag ...] < Amount IncomeAccount (Amount|_) Account ... Narration
+    ///
+    /// The source account (spend or income) can also be split into `(Amount|_) Account` pairs,
+    /// which are ended by a `|` if the leg accounts are split as well. The leg accounts can be in
+    /// another currency with `@ Price` after them, which is the price of the source amounts.
+    /// An amount with a `+` sign is an income like `<`, and one with a `-` sign reverses the
+    /// postings, e.g. for a refund.
+    /// The date is today unless the first argument is a `YYYY-MM-DD` or `MM-DD` date.
+    /// The currency of the amount can be a separate argument after it, e.g. `10 USD`.
+    /// A single expense account can be omitted if `fallback_expense_account` is configured.
     pub fn today_from_command(
+        cmds: &'am [String],
+        accounts: &'ac [String],
+        config: &Beancount,
+        ctx: &ParseContext<'am>,
+    ) -> Result<Self> {
+        let today = ledger_today(config.day_cutoff_hour);
+        let args = CommandArgs::split(cmds, accounts, config, ctx, today)?;
+        let (number, currency) =
+            Amount::parse(args.amount).ok_or_else(|| anyhow!("Invalid amount {}", args.amount))?;
+        let currency = currency.or(args.currency);
+        let mut notes = Vec::new();
+        if let Some(account) = args.fallback {
+            notes.push(format!("ℹ️ No expense account given, using {}", account));
+        }
+        if currency.is_none() && config.mark_default_currency {
+            notes.push(format!(
+                "ℹ️ No currency given, using {}",
+                ctx.default_currency
+            ));
+        }
+        let amount = Amount {
+            number,
+            currency: currency.unwrap_or(ctx.default_currency),
+        };
+        if args.price.is_none() {
+            notes.extend(conversion_note(&amount, config));
+        }
+        let (sources, legs, fuzzy) = args.resolve_accounts(accounts, config)?;
+        let postings = args.solve_postings(&amount, sources, legs)?;
+        Self::from_postings(args, postings, fuzzy, notes, config, ctx, today)
+    }
+
+    /// Finishes a transaction parsed from a command with its postings: flags it, signs and rounds
+    /// the amounts, fills in the narration, the tags and the metadata, and validates it
+    fn from_postings(
+        args: CommandArgs<'_, '_>,
+        mut postings: Vec<Posting<'ac, 'am>>,
+        fuzzy: bool,
+        mut notes: Vec<String>,
+        config: &Beancount,
+        ctx: &ParseContext<'_>,
+        today: NaiveDate,
+    ) -> Result<Self> {
+        let CommandArgs {
+            date,
+            payee,
+            narration,
+            mut tags,
+            income,
+            ..
+        } = args;
+        let sep = config.account_separator.as_str();
+        add_account_tags(&mut tags, &postings, &config.account_tags, sep);
+
+        let narration = match narration.as_str() {
+            "" => postings
+                .first()
+                .and_then(|p| config.narrations.get(p.account))
+                .cloned()
+                .or_else(|| {
+                    let template = config.payee_narration.as_ref()?;
+                    Some(template.replace("{payee}", payee.as_ref()?))
+                })
+                .unwrap_or(narration),
+            _ => narration,
+        };
+        add_keyword_tags(&mut tags, &narration, &config.keyword_tags);
+
+        // transactions with fuzzily matched accounts need to be reviewed
+        let flag = if fuzzy {
+            FLAG_INCOMPLETE
+        } else {
+            FLAG_COMPLETE
+        };
+        // amounts are entered as the changes of the expense postings
+        if !income && config.expense_sign == ExpenseSign::Negative {
+            for posting in postings.iter_mut() {
+                posting.amount.number = -posting.amount.number;
+            }
+        }
+        round_postings(&mut postings, &config.precision, config.rounding);
+        check_balance(&postings)?;
+        if config.preview_totals {
+            notes.push(totals_note(&postings));
+        }
+
+        let mut metadata = Vec::new();
+        if let (true, Some(user)) = (config.entered_by, ctx.user) {
+            metadata.push(("entered-by".to_string(), user.to_string()));
+        }
+
+        let txn = Self {
+            date: date.unwrap_or(today),
+            flag,
+            payee,
+            narration,
+            tags,
+            metadata,
+            postings,
+            notes,
+            align_amounts: config.align_amounts,
+            txn_keyword: config.txn_keyword,
+            posting_order: config.posting_order,
+        };
+        txn.validate(config, today)?;
+        Ok(txn)
+    }
+
+    /// Checks the transaction against the configured limits, given the ledger date `today`
+    fn validate(&self, config: &Beancount, today: NaiveDate) -> Result<()> {
+        ensure!(
+            self.postings.len() <= config.max_postings,
+            "Too many postings: {} (maximum {})",
+            self.postings.len(),
+            config.max_postings
+        );
+        if let Some(ref payee) = self.payee {
+            check_length("Payee", payee, config.max_payee_length)?;
+        }
+        if self.narration.is_empty() && config.require_narration {
+            return Err(anyhow!("Empty narration"));
+        }
+        check_length("Narration", &self.narration, config.max_narration_length)?;
+        if let Some(days) = config.max_future_days {
+            ensure!(
+                self.date <= today + chrono::Duration::days(days),
+                "Date {} is more than {} days in the future",
+                self.date,
+                days
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<'ac, 'am: 'ac> Transaction<'ac, 'am> {
+    /// Parses an opening balance transaction of an asset or liability account from a command.
+    /// AccountTerm ... Amount
+    pub fn opening_balance_from_command(
         cmds: &'am [String],
         accounts: &'ac [String],
         default_currency: &'am str,
+        config: &'ac Beancount,
+    ) -> Result<Self> {
+        let (term, amount) = split_trailing_amount(cmds, default_currency)
+            .ok_or_else(|| anyhow!("Not enough arguments: amount"))?;
+        ensure!(!term.is_empty(), "Not enough arguments: account");
+        let equity = config.opening_balances_account.as_str();
+        ensure!(
+            accounts.iter().any(|ac| ac == equity),
+            "Opening balances account {} is not opened",
+            equity
+        );
+        let sep = config.account_separator.as_str();
+        let mode = config.match_mode;
+        let (account, _) = filter_account(accounts, &term.join(" "), sep, mode, |x| {
+            has_root(x, "Assets", sep) || has_root(x, "Liabilities", sep)
+        })
+        .context("Invalid asset account")?;
+        let mut postings = vec![
+            Posting::new(account, amount.clone()),
+            Posting::new(equity, -amount),
+        ];
+        round_postings(&mut postings, &config.precision, config.rounding);
+
+        Ok(Self {
+            date: ledger_today(config.day_cutoff_hour),
+            flag: FLAG_COMPLETE,
+            payee: None,
+            narration: "Opening balance".to_string(),
+            tags: Vec::new(),
+            metadata: Vec::new(),
+            postings,
+            notes: Vec::new(),
+            align_amounts: config.align_amounts,
+            txn_keyword: config.txn_keyword,
+            posting_order: config.posting_order,
+        })
+    }
+}
+
+impl<'ac, 'am: 'ac> Transaction<'ac, 'am> {
+    /// Parses a transaction reusing the flag, the payee, the narration, the tags and the accounts
+    /// of the rendered transaction `prev` of two postings, with the amount `cmd_amount` dated
+    /// today
+    pub fn from_previous(
+        prev: &'am str,
+        cmd_amount: &'am str,
+        accounts: &'ac [String],
+        config: &Beancount,
+        ctx: &ParseContext<'am>,
+    ) -> Result<Self> {
+        let mut lines = preview_transaction(prev).lines();
+        let header = lines.next().unwrap_or_default();
+        ensure!(
+            transaction_date(header).is_some(),
+            "The replied message is not a transaction"
+        );
+        // the date and the flag, then the quoted payee and narration, followed by the tags
+        let (strings, tags) = header.split_at(header.rfind('"').map_or(0, |i| i + 1));
+        let fields = command_split(strings).map_err(|e| anyhow!("Invalid transaction: {}", e))?;
+        let (payee, narration) = match fields.get(2..) {
+            Some([narration]) => (None, narration.clone()),
+            Some([payee, narration]) => (Some(payee.clone()), narration.clone()),
+            _ => bail!("Invalid transaction: {}", header),
+        };
+        let tags: Vec<_> = tags.split_whitespace().map(ToString::to_string).collect();
+        ensure!(
+            tags.iter().all(|tag| tag.starts_with('#')),
+            "Invalid transaction: {}",
+            header
+        );
+        // a transaction to be reviewed stays so
+        let flag = transaction_flag(header).unwrap_or(FLAG_COMPLETE);
+
+        // metadata lines have keys ending with colons
+        let prev_postings: Vec<Vec<_>> = lines
+            .map(|line| line.split_ascii_whitespace().collect::<Vec<_>>())
+            .filter(|xs| xs.len() >= 3 && !xs[0].ends_with(':'))
+            .collect();
+        ensure!(
+            prev_postings.len() == 2 && prev_postings.iter().all(|xs| xs.len() == 3),
+            "Only transactions of two postings without prices can be reused"
+        );
+        let account = |name: &str| {
+            accounts
+                .iter()
+                .find(|ac| *ac == name)
+                .ok_or_else(|| anyhow!("Account {} is not opened", name))
+        };
+        let (number, currency) =
+            Amount::parse(cmd_amount).ok_or_else(|| anyhow!("Invalid amount {}", cmd_amount))?;
+        let prev_number: Decimal = prev_postings[0][1]
+            .parse()
+            .map_err(|_| anyhow!("Invalid amount {}", prev_postings[0][1]))?;
+        // the postings keep their signs
+        let amount = Amount {
+            number: if prev_number.is_sign_negative() {
+                -number
+            } else {
+                number
+            },
+            currency: currency.unwrap_or(prev_postings[0][2]),
+        };
+        let mut postings = vec![
+            Posting::new(account(prev_postings[0][0])?, amount.clone()),
+            Posting::new(account(prev_postings[1][0])?, -amount),
+        ];
+        round_postings(&mut postings, &config.precision, config.rounding);
+
+        let mut metadata = Vec::new();
+        if let (true, Some(user)) = (config.entered_by, ctx.user) {
+            metadata.push(("entered-by".to_string(), user.to_string()));
+        }
+
+        let today = ledger_today(config.day_cutoff_hour);
+        let txn = Self {
+            date: today,
+            flag,
+            payee,
+            narration,
+            tags,
+            metadata,
+            postings,
+            notes: Vec::new(),
+            align_amounts: config.align_amounts,
+            txn_keyword: config.txn_keyword,
+            posting_order: config.posting_order,
+        };
+        txn.validate(config, today)?;
+        Ok(txn)
+    }
+}
+
+/// Arguments of a transaction command, split by [`CommandArgs::split`] before resolving the
+/// accounts
+struct CommandArgs<'am, 'c> {
+    date: Option<NaiveDate>,
+    payee: Option<String>,
+    narration: String,
+    tags: Vec<String>,
+    /// Whether the amount is an income from the source accounts into the leg accounts
+    income: bool,
+    amount: &'am str,
+    /// Currency of the amount entered as a separate argument
+    currency: Option<&'am str>,
+    /// `(Amount|_) Account` pairs of the source accounts
+    sources: Vec<(&'am str, &'am str)>,
+    /// `(Amount|_) Account` pairs of the leg accounts
+    legs: Vec<(&'am str, &'c str)>,
+    /// The fallback expense account used as the omitted leg account
+    fallback: Option<&'c str>,
+    /// Price of the source amounts in the currency of the legs
+    price: Option<&'am str>,
+}
+
+impl<'am: 'c, 'c> CommandArgs<'am, 'c> {
+    /// Splits the arguments of a transaction command entered on the ledger date `today`, see
+    /// [`Transaction::today_from_command`]
+    fn split(
+        cmds: &'am [String],
+        accounts: &[String],
+        config: &'c Beancount,
+        ctx: &ParseContext<'_>,
+        today: NaiveDate,
     ) -> Result<Self> {
         let mut iter = cmds.iter().peekable();
-        let payee = iter
-            .next_if(|x| x.starts_with('>'))
-            .map(|s| s[1..].to_string());
+        let date = cmds.first().and_then(|x| parse_date(x, today));
+        if date.is_some() {
+            iter.next();
+        }
+        let cmd_payee = iter.next_if(|x| x.starts_with('>')).map(|s| &s[1..]);
 
         let mut tags = Vec::new();
         while let Some(tag) = iter.next_if(|x| x.starts_with('#')) {
-            tags.push(tag.to_string());
+            tags.push(normalize_tag(tag, config.tag_spaces)?);
         }
 
-        let cmd_amount = iter
+        // income from an income account into the leg accounts, also entered as a `+` amount
+        let income = iter.next_if(|x| x.as_str() == INCOME).is_some()
+            || iter.peek().is_some_and(|x| x.starts_with('+'));
+        let leg_name = if income { "account" } else { "expense account" };
+
+        let amount = iter
             .next()
             .ok_or_else(|| anyhow!("Not enough arguments: amount"))?;
-        let cmd_spd_acc = iter
-            .next()
-            .ok_or_else(|| anyhow!("Not enough arguments: account"))?;
-        let cmd_exp_acc = iter
-            .next()
-            .ok_or_else(|| anyhow!("Not enough arguments: expense account"))?;
-        let narration = iter.map(|x| x.as_str()).collect::<Vec<_>>().join(" ");
-        // if narration.is_empty() {
-        //     return Err(anyhow!("Empty narration"));
-        // }
-        let amount = Amount::from_str(cmd_amount, default_currency)
-            .ok_or_else(|| anyhow!("Invalid amount {}", cmd_amount))?;
-
-        let account = filter_account(accounts, cmd_spd_acc, |x| !x.starts_with("Expenses:"))
-            .context("Invalid spend account")?;
-        let expense_account = filter_account(accounts, cmd_exp_acc, |x| x.starts_with("Expenses:"))
-            .context("Invalid expense account")?;
-        let postings = vec![
-            Posting::new(expense_account, amount.clone()),
-            Posting::new(account, -amount),
-        ];
-
-        let date = naive_today();
+        // `10 CNY` entered as two arguments
+        let currency = iter.next_if(|x| {
+            Amount::parse(amount).is_some_and(|(_, currency)| currency.is_none())
+                && separate_currency(x, accounts, config, ctx)
+        });
+        // numeric account shortcuts are accounts rather than split amounts
+        let is_split_amount = |x: &&String| {
+            (x.as_str() == REMAINDER || Amount::parse(x).is_some())
+                && !config.account_numbers.contains_key(x.as_str())
+        };
+        // either a single source account, or `Amount Account` pairs of a split
+        let mut sources = Vec::new();
+        while let Some(source) = iter.next_if(is_split_amount) {
+            let cmd_spd_acc = iter
+                .next()
+                .ok_or_else(|| anyhow!("Not enough arguments: account of {}", source))?;
+            sources.push((source.as_str(), cmd_spd_acc.as_str()));
+        }
+        if sources.is_empty() {
+            let cmd_spd_acc = iter
+                .next()
+                .ok_or_else(|| anyhow!("Not enough arguments: account"))?;
+            sources.push((REMAINDER, cmd_spd_acc.as_str()));
+        }
+        iter.next_if(|x| x.as_str() == SPLIT_SEPARATOR);
+        // either a single leg account, or `Amount Account` pairs of a split
+        let mut legs = Vec::new();
+        while let Some(leg) = iter.next_if(is_split_amount) {
+            let cmd_leg_acc = iter
+                .next()
+                .ok_or_else(|| anyhow!("Not enough arguments: {} of {}", leg_name, leg))?;
+            legs.push((leg.as_str(), cmd_leg_acc.as_str()));
+        }
+        // the fallback expense account is used if the only leg account is omitted
+        let mut fallback = None;
+        if legs.is_empty() {
+            let cmd_leg_acc = match (iter.next(), &config.fallback_expense_account) {
+                (Some(cmd_leg_acc), _) => cmd_leg_acc.as_str(),
+                (None, Some(account)) if !income => {
+                    fallback = Some(account.as_str());
+                    account.as_str()
+                }
+                (None, _) => bail!("Not enough arguments: {}", leg_name),
+            };
+            legs.push((REMAINDER, cmd_leg_acc));
+        }
+        // `@` is only a price if followed by an amount, e.g. not in `@ home`
+        let mut ahead = iter.clone();
+        let price = match (ahead.next(), ahead.next()) {
+            (Some(x), Some(price)) if x.as_str() == PRICE && Amount::parse(price).is_some() => {
+                iter.nth(1).map(String::as_str)
+            }
+            (Some(x), None) if x.as_str() == PRICE => bail!("Not enough arguments: price"),
+            _ => None,
+        };
+        // the rest is the narration as it is, even if it looks like amounts or accounts
+        let rest = iter.map(|x| x.as_str()).collect::<Vec<_>>().join(" ");
+        // with the fields swapped, `>` gives the narration and the rest gives the payee
+        let (payee, narration) = if config.swap_payee_narration {
+            let payee = Some(rest).filter(|p| !p.is_empty());
+            (payee, cmd_payee.unwrap_or_default().to_string())
+        } else {
+            (cmd_payee.map(ToString::to_string), rest)
+        };
+        // pasted commands may quote stray spaces
+        let (payee, narration) = if config.keep_surrounding_spaces {
+            (payee, narration)
+        } else {
+            let payee = payee
+                .map(|p| p.trim().to_string())
+                .filter(|p| !p.is_empty());
+            (payee, narration.trim().to_string())
+        };
+        let payee = payee.or_else(|| ctx.default_payee.map(ToString::to_string));
 
         Ok(Self {
             date,
             payee,
             narration,
             tags,
-            postings,
+            income,
+            amount,
+            currency: currency.map(String::as_str),
+            sources,
+            legs,
+            fallback,
+            price,
         })
     }
-}
 
-/// Appends `text` to a file
-pub fn append_to_file(text: &str, filename: impl AsRef<Path>) -> io::Result<()> {
-    let parent = filename
-        .as_ref()
-        .parent()
-        .expect("there should be a parent");
-    if !parent.exists() {
-        fs::create_dir(parent)?;
+    /// Resolves the source accounts and the leg accounts. The returned boolean is true if any of
+    /// them is matched fuzzily.
+    fn resolve_accounts<'ac>(
+        &self,
+        accounts: &'ac [String],
+        config: &Beancount,
+    ) -> Result<(Vec<&'ac str>, Vec<&'ac str>, bool)> {
+        let sep = config.account_separator.as_str();
+        let mut fuzzy = false;
+        let mut sources = Vec::with_capacity(self.sources.len());
+        for (_, cmd_spd_acc) in self.sources.iter() {
+            let (account, spd_fuzzy) = if self.income {
+                filter_numbered_account(accounts, cmd_spd_acc, config, |x| {
+                    has_root(x, "Income", sep)
+                })
+                .context("Invalid income account")?
+            } else {
+                let spend = filter_numbered_account(accounts, cmd_spd_acc, config, |x| {
+                    !has_root(x, "Expenses", sep)
+                });
+                if spend.is_err() {
+                    // a common mistake of entering an expense account in place of the spend account
+                    let expense = filter_numbered_account(accounts, cmd_spd_acc, config, |x| {
+                        has_root(x, "Expenses", sep)
+                    });
+                    if let Ok((expense, _)) = expense {
+                        bail!(
+                            "Spend account must be an asset or a liability, not an expense: {}",
+                            expense
+                        );
+                    }
+                }
+                spend.context("Invalid spend account")?
+            };
+            fuzzy |= spd_fuzzy;
+            sources.push(account.as_str());
+        }
+        let leg_name = if self.income {
+            "account"
+        } else {
+            "expense account"
+        };
+        let mut legs = Vec::with_capacity(self.legs.len());
+        for (_, cmd_leg_acc) in self.legs.iter() {
+            let (leg_account, leg_fuzzy) = if self.fallback.is_some() {
+                let account = accounts
+                    .iter()
+                    .find(|ac| ac == cmd_leg_acc)
+                    .ok_or_else(|| {
+                        anyhow!("Fallback expense account {} is not opened", cmd_leg_acc)
+                    })?;
+                (account, false)
+            } else {
+                filter_numbered_account(accounts, cmd_leg_acc, config, |x| {
+                    if self.income {
+                        !has_root(x, "Income", sep)
+                    } else {
+                        has_root(x, "Expenses", sep)
+                    }
+                })
+                .with_context(|| format!("Invalid {}", leg_name))?
+            };
+            fuzzy |= leg_fuzzy;
+            legs.push(leg_account.as_str());
+        }
+        Ok((sources, legs, fuzzy))
     }
-    let mut fw = fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(filename)?;
-    // have to seek end, otherwise the stream_position method will return 0
-    fw.seek(SeekFrom::End(0))?;
-    if fw.stream_position()? != 0 {
-        writeln!(fw)?;
+
+    /// Solves the amounts of the resolved `sources` and `legs` from the total `amount`, returning
+    /// the postings of the legs followed by those of the sources. With a price, the legs are in
+    /// the currency of the price, converted from the total.
+    fn solve_postings<'ac>(
+        &self,
+        amount: &Amount<'am>,
+        sources: Vec<&'ac str>,
+        legs: Vec<&'ac str>,
+    ) -> Result<Vec<Posting<'ac, 'am>>> {
+        let (leg_total, price) = match self.price {
+            Some(cmd_price) => {
+                let (rate, currency) = Amount::parse(cmd_price)
+                    .ok_or_else(|| anyhow!("Invalid price {}", cmd_price))?;
+                let currency = currency
+                    .or_else(|| {
+                        self.legs
+                            .iter()
+                            .find_map(|leg| Amount::parse(leg.0).and_then(|a| a.1))
+                    })
+                    .ok_or_else(|| anyhow!("Currency of price {} is unknown", cmd_price))?;
+                ensure!(
+                    currency != amount.currency,
+                    "Price {} is in the currency of the amount",
+                    cmd_price
+                );
+                let price = Amount {
+                    number: rate,
+                    currency,
+                };
+                let leg_total = Amount {
+                    number: amount.number * rate,
+                    currency,
+                };
+                (leg_total, Some(price))
+            }
+            None => (amount.clone(), None),
+        };
+        let source_amounts = solve_legs(amount, self.sources.iter().map(|source| source.0))?;
+        let leg_amounts = solve_legs(&leg_total, self.legs.iter().map(|leg| leg.0))?;
+        let mut postings: Vec<_> = legs
+            .into_iter()
+            .zip(leg_amounts)
+            .map(|(account, amount)| Posting::new(account, amount))
+            .collect();
+        for (account, amount) in sources.into_iter().zip(source_amounts) {
+            let mut posting = Posting::new(account, -amount);
+            posting.price = price.clone();
+            postings.push(posting);
+        }
+        Ok(postings)
     }
-    writeln!(fw, "{}", text)?;
-    Ok(())
 }
 
-impl<'ac, 'am> Posting<'ac, 'am> {
-    pub fn new(account: &'ac str, amount: Amount<'am>) -> Self {
-        Self { account, amount }
+/// Amount of a split leg that is the remainder of the total
+const REMAINDER: &str = "_";
+/// Marker of an income transaction
+const INCOME: &str = "<";
+/// Determines whether the argument `x` after a bare number is the currency of the amount rather
+/// than an account. Known currencies are always taken as currencies, other currency-like
+/// arguments only if they match no accounts.
+fn separate_currency(
+    x: &str,
+    accounts: &[String],
+    config: &Beancount,
+    ctx: &ParseContext<'_>,
+) -> bool {
+    if !is_currency(x) || config.account_numbers.contains_key(x) {
+        return false;
     }
+    let known = x == ctx.default_currency
+        || x == config.default_currency
+        || config.precision.contains_key(x)
+        || config.rates.contains_key(x)
+        || config.currency_symbols.get(x).is_some();
+    known || find_accounts(accounts, x).is_empty()
 }
 
-impl<'a> Amount<'a> {
-    pub fn from_str(s: &'a str, default_currency: &'a str) -> Option<Self> {
-        let regex = regex!(r"^([0-9.]+)\s*([A-Z][A-Z0-9'._-]{0,22}[A-Z0-9])?$");
-        let caps = regex.captures(s)?;
-        let number: Decimal = caps.get(1).and_then(|n| n.as_str().parse().ok())?;
-        let currency = caps.get(2).map_or(default_currency, |c| c.as_str());
-        Some(Self { number, currency })
+/// Like [`filter_account`], but takes the configured numeric shortcuts of accounts as their
+/// exact names
+fn filter_numbered_account<'a>(
+    accounts: &'a [String],
+    term: &str,
+    config: &Beancount,
+    pred: impl Fn(&&String) -> bool,
+) -> Result<(&'a String, bool)> {
+    let name = match config.account_numbers.get(term) {
+        Some(name) => name,
+        None => {
+            let sep = &config.account_separator;
+            return filter_account(accounts, term, sep, config.match_mode, pred);
+        }
+    };
+    let account = accounts
+        .iter()
+        .find(|ac| *ac == name)
+        .ok_or_else(|| anyhow!("Account {} of shortcut {} is not opened", name, term))?;
+    ensure!(
+        pred(&account),
+        "Account {} of shortcut {} does not fit here",
+        name,
+        term
+    );
+    Ok((account, false))
+}
+
+/// Returns the note of `amount` converted to the default currency by the configured rates
+fn conversion_note(amount: &Amount<'_>, config: &Beancount) -> Option<String> {
+    let home = &config.default_currency;
+    if amount.currency == home {
+        return None;
     }
+    let rate = *config.rates.get(amount.currency)?;
+    let dp = config.precision.get(home).copied().unwrap_or(2);
+    let converted = (amount.number * rate).round_dp(dp);
+    Some(format!(
+        "ℹ️ ≈ {} {} at {} {}/{}",
+        converted, home, rate, home, amount.currency
+    ))
 }
 
-impl<'a> std::ops::Neg for Amount<'a> {
-    type Output = Self;
-    fn neg(self) -> Self::Output {
-        Self {
-            number: -self.number,
-            currency: self.currency,
+/// Marker of the price of the source accounts
+const PRICE: &str = "@";
+/// Separator between the split source accounts and the split leg accounts
+const SPLIT_SEPARATOR: &str = "|";
+
+/// Solves the amounts of split legs adding up to `total`. At most one leg can be the remainder
+/// `_`, otherwise the legs must add up to the total. Legs without a currency are in the currency
+/// of the total.
+fn solve_legs<'a>(
+    total: &Amount<'a>,
+    legs: impl Iterator<Item = &'a str>,
+) -> Result<Vec<Amount<'a>>> {
+    let mut amounts = Vec::new();
+    let mut remainder = None;
+    let mut sum = Decimal::ZERO;
+    for leg in legs {
+        if leg == REMAINDER {
+            ensure!(remainder.is_none(), "More than one split amount is omitted");
+            remainder = Some(amounts.len());
+            amounts.push(Amount {
+                number: Decimal::ZERO,
+                currency: total.currency,
+            });
+        } else {
+            let amount = Amount::from_str(leg, total.currency)
+                .ok_or_else(|| anyhow!("Invalid amount {}", leg))?;
+            ensure!(
+                amount.currency == total.currency,
+                "Split amount {} is not in {}",
+                amount,
+                total.currency
+            );
+            sum += amount.number;
+            amounts.push(amount);
         }
     }
+    match remainder {
+        Some(i) => amounts[i].number = total.number - sum,
+        None => ensure!(
+            sum == total.number,
+            "Split amounts add up to {} {}, not {}",
+            sum,
+            total.currency,
+            total
+        ),
+    }
+    Ok(amounts)
 }
 
-// Displays
-impl<'ac, 'am> fmt::Display for Transaction<'ac, 'am> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // first line
-        write!(f, "{} *", self.date.format("%F"))?;
-        if let Some(ref payee) = self.payee {
-            write!(f, r#" "{}""#, escape_string(payee))?;
-        }
-        write!(f, r#" "{}""#, escape_string(&self.narration))?;
-        for tag in self.tags.iter() {
-            write!(f, " {}", tag)?;
-        }
-        writeln!(f)?;
+/// Makes sure the postings balance, using the prices of priced postings. A residual is tolerated
+/// if it is within half of the last digit of the non-integer amounts in its currency, as in
+/// beancount.
+fn check_balance(postings: &[Posting]) -> Result<()> {
+    let mut residuals: HashMap<&str, Decimal> = HashMap::new();
+    let mut tolerances: HashMap<&str, Decimal> = HashMap::new();
+    for posting in postings {
+        let weight = match posting.price {
+            Some(ref price) => Amount {
+                number: posting.amount.number * price.number,
+                currency: price.currency,
+            },
+            None => {
+                // integers do not infer tolerances
+                let amount = &posting.amount;
+                if amount.number.scale() > 0 {
+                    let tolerance = Decimal::new(5, amount.number.scale() + 1);
+                    let max = tolerances.entry(amount.currency).or_insert(tolerance);
+                    *max = (*max).max(tolerance);
+                }
+                posting.amount.clone()
+            }
+        };
+        *residuals.entry(weight.currency).or_insert(Decimal::ZERO) += weight.number;
+    }
+    for (currency, residual) in residuals {
+        let tolerance = tolerances.get(currency).copied().unwrap_or(Decimal::ZERO);
+        ensure!(
+            residual.abs() <= tolerance,
+            "Transaction does not balance: {} {}",
+            residual.normalize(),
+            currency
+        );
+    }
+    Ok(())
+}
 
-        // postings
-        for posting in self.postings.iter() {
-            writeln!(f, "    {}", posting)?;
-        }
-        // TODO: trim out the last \n
-        Ok(())
+/// Returns the note of the totals of the positive postings per currency, i.e. what the split legs
+/// of a transaction add up to
+fn totals_note(postings: &[Posting]) -> String {
+    let mut totals: BTreeMap<&str, Decimal> = BTreeMap::new();
+    for posting in postings.iter().filter(|p| p.amount.number > Decimal::ZERO) {
+        *totals.entry(posting.amount.currency).or_default() += posting.amount.number;
     }
+    let totals: Vec<_> = totals
+        .iter()
+        .map(|(currency, total)| format!("{} {}", total.normalize(), currency))
+        .collect();
+    format!("ℹ️ Total: {}", totals.join(", "))
 }
 
-impl<'ac, 'am> fmt::Display for Posting<'ac, 'am> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.account, self.amount)
+/// Splits the amount off the end of `cmds`. The amount can either be one token like `10 CNY`, or
+/// two tokens `10` and `CNY`.
+fn split_trailing_amount<'a>(
+    cmds: &'a [String],
+    default_currency: &'a str,
+) -> Option<(&'a [String], Amount<'a>)> {
+    let (last, rest) = cmds.split_last()?;
+    if let Some(amount) = Amount::from_str(last, default_currency) {
+        return Some((rest, amount));
+    }
+    let (number, rest) = rest.split_last()?;
+    if !is_currency(last) {
+        return None;
     }
+    let number = number.parse().ok()?;
+    Some((
+        rest,
+        Amount {
+            number,
+            currency: last,
+        },
+    ))
 }
 
-impl<'a> fmt::Display for Amount<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.number, self.currency)
+/// Determines whether `s` is a valid beancount currency
+pub fn is_currency(s: &str) -> bool {
+    regex!(r"^[A-Z][A-Z0-9'._-]{0,22}[A-Z0-9]$").is_match(s)
+}
+
+/// Expands a command starting with a shortcut keyword, i.e.
+/// [>Payee] [#Tag ...] Keyword Amount Narration
+/// into a full command with the accounts of the shortcut.
+pub fn expand_shortcut(cmds: &mut Vec<String>, shortcuts: &HashMap<String, Shortcut>) {
+    let pos = match cmds
+        .iter()
+        .position(|x| !x.starts_with('>') && !x.starts_with('#'))
+    {
+        Some(pos) => pos,
+        None => return,
+    };
+    // the amount has to follow the keyword
+    if pos + 1 >= cmds.len() {
+        return;
+    }
+    if let Some(shortcut) = shortcuts.get(&cmds[pos].to_lowercase()) {
+        cmds.remove(pos);
+        cmds.insert(pos + 1, shortcut.account.clone());
+        cmds.insert(pos + 2, shortcut.expense.clone());
     }
 }
 
-pub fn get_accounts(path: impl AsRef<Path>) -> io::Result<Vec<String>> {
-    // TODO: categorize accounts to accounts/*.bean
-    // assuming all accounts are in {root}/accounts.bean
-    let account_path = BufReader::new(File::open(path.as_ref().join("accounts.bean"))?);
-    let mut ret = Vec::new();
-    for line in account_path.lines() {
-        let line = line?;
-        let xs = line
-            .split_ascii_whitespace()
-            .map(ToString::to_string)
-            .collect::<Vec<_>>();
-        if xs.len() < 3 || xs[0].starts_with(';') {
-            continue;
-        }
-        match xs[1].as_str() {
-            "open" => {
-                // sadly, we have to clone here
-                //   https://users.rust-lang.org/t/why-cant-move-element-of-vector/30454/4
-                ret.push(xs[2].clone());
+/// Rounds the amounts of `postings` to the precisions of their currencies. The rounding error of
+/// each currency is absorbed into its largest rounded posting, so that the postings still sum up
+/// to the (rounded) original total.
+fn round_postings(postings: &mut [Posting], precision: &HashMap<String, u32>, rounding: Rounding) {
+    let strategy = match rounding {
+        Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+    };
+    for (currency, &dp) in precision.iter() {
+        let round = |n: Decimal| n.round_dp_with_strategy(dp, strategy);
+        let mut total = Decimal::ZERO;
+        let mut rounded_total = Decimal::ZERO;
+        let mut largest: Option<usize> = None;
+        for i in 0..postings.len() {
+            let amount = &mut postings[i].amount;
+            if amount.currency != currency {
+                continue;
             }
-            "close" => {
-                // TODO: remove closed accounts
+            let rounded = round(amount.number);
+            total += amount.number;
+            rounded_total += rounded;
+            if rounded != amount.number {
+                amount.number = rounded;
+                if largest.is_none_or(|l| rounded.abs() > postings[l].amount.number.abs()) {
+                    largest = Some(i);
+                }
             }
-            _ => {}
+        }
+        if let Some(i) = largest {
+            postings[i].amount.number += round(total) - rounded_total;
         }
     }
-    Ok(ret)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn test_matches() {
-        assert!(account_matches("Expenses:Transport:Public:Bus", "bus"));
-        assert!(account_matches("Expenses:Transport:Bus", "transp bus"));
-        assert!(account_matches("Expenses:Transport:Bus", " transp  bus "));
+/// Adds the tags configured in `keyword_tags` for the keywords `narration` contains, ignoring case,
+/// skipping the tags already in `tags`
+fn add_keyword_tags(
+    tags: &mut Vec<String>,
+    narration: &str,
+    keyword_tags: &HashMap<String, String>,
+) {
+    let narration = narration.to_lowercase();
+    let mut added: Vec<_> = keyword_tags
+        .iter()
+        .filter(|(keyword, _)| narration.contains(&keyword.to_lowercase()))
+        .map(|(_, tag)| match tag.starts_with('#') {
+            true => tag.clone(),
+            false => format!("#{}", tag),
+        })
+        .collect();
+    added.sort();
+    added.dedup();
+    for tag in added {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
     }
+}
 
-    #[test]
-    fn test_filter() {
-        let accounts: Vec<_> = vec![
-            "Assets:Cash:CNY",
-            "Assets:Cash:USD",
-            "Expenses:International:Fees",
-            "Expenses:Food:Groceries",
-            "Expenses:Health:Dental:Insurance",
-            "Expenses:Health:Life:GroupTermLife",
-            "Expenses:Health:Medical:Insurance",
-            "Expenses:Health:Vision:Insurance",
-            "Expenses:Home:Internet",
-            "Expenses:Home:Phone",
-            "Expenses:Home:Rent",
-            "Expenses:Tele:Mail",
-            "Expenses:Tele:Email",
-        ]
+/// Adds the tags configured in `account_tags` for the accounts of `postings` or their parents,
+/// skipping the tags already in `tags`
+fn add_account_tags(
+    tags: &mut Vec<String>,
+    postings: &[Posting],
+    account_tags: &HashMap<String, String>,
+    sep: &str,
+) {
+    let mut added: Vec<_> = account_tags
         .iter()
-        .map(ToString::to_string)
+        .filter(|(prefix, _)| {
+            postings.iter().any(|p| {
+                p.account
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(sep))
+            })
+        })
+        .map(|(_, tag)| match tag.starts_with('#') {
+            true => tag.clone(),
+            false => format!("#{}", tag),
+        })
         .collect();
-        let pred = |s: &&String| s.starts_with("Expenses:");
-        assert!(
-            format!("{}", filter_account(&accounts, "insur", pred).unwrap_err())
-                .starts_with("More than one last-component matched account: ")
-        );
-        assert!(format!(
-            "{}",
-            filter_account(&accounts, "insurance", pred).unwrap_err()
-        )
-        .starts_with("More than one last-component exact-match account: "));
-        assert!(
-            format!("{}", filter_account(&accounts, "health", pred).unwrap_err())
-                .starts_with("More than one matched account: ")
-        );
-        // whole account unique match
-        assert_eq!(
-            filter_account(&accounts, "dental", pred).unwrap(),
-            "Expenses:Health:Dental:Insurance"
-        );
-        // last component unique match
-        assert_eq!(
-            filter_account(&accounts, "inter", pred).unwrap(),
-            "Expenses:Home:Internet"
-        );
-        // last component unique exact match
-        assert_eq!(
-            filter_account(&accounts, "mail", pred).unwrap(),
-            "Expenses:Tele:Mail"
-        );
-        // multiple terms match
-        assert_eq!(
-            filter_account(&accounts, "med insur", pred).unwrap(),
-            "Expenses:Health:Medical:Insurance"
-        );
+    added.sort();
+    for tag in added {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+}
+
+/// Handles whitespace in a tag, which is not allowed by beancount
+fn normalize_tag(tag: &str, spaces: TagSpaces) -> Result<String> {
+    if !tag.contains(char::is_whitespace) {
+        return Ok(tag.to_string());
+    }
+    match spaces {
+        TagSpaces::Reject => bail!("Tag '{}' contains whitespace", tag),
+        TagSpaces::Hyphen => Ok(tag.split_whitespace().collect::<Vec<_>>().join("-")),
+    }
+}
+
+fn check_length(field: &str, value: &str, max: usize) -> Result<()> {
+    let len = value.chars().count();
+    if len > max {
+        bail!("{} too long: {} characters (maximum {})", field, len, max);
+    }
+    Ok(())
+}
+
+/// Returns the flag of a rendered transaction, where the `txn` keyword is the `*` flag
+pub fn transaction_flag(txn: &str) -> Option<char> {
+    let (_, rest) = txn.split_once(' ')?;
+    if rest.starts_with("txn ") {
+        return Some(FLAG_COMPLETE);
+    }
+    rest.chars().next()
+}
+
+/// Replaces the flag of a rendered transaction with `flag`
+pub fn set_transaction_flag(txn: &str, flag: char) -> Option<String> {
+    let (date, rest) = txn.split_once(' ')?;
+    let old_flag = rest.chars().next()?;
+    Some(format!("{} {}{}", date, flag, &rest[old_flag.len_utf8()..]))
+}
+
+/// Adds a metadata line right after the first line of a rendered transaction
+pub fn add_transaction_metadata(txn: &str, key: &str, value: &str) -> String {
+    let (first, rest) = txn.split_once('\n').unwrap_or((txn, ""));
+    format!(
+        "{}\n    {}: \"{}\"\n{}",
+        first,
+        key,
+        escape_string(value),
+        rest
+    )
+}
+
+/// Returns the first posting amount of a rendered transaction whose magnitude is above
+/// `threshold`, e.g. `10000 CNY`
+pub fn amount_above(txn: &str, threshold: Decimal) -> Option<String> {
+    txn.lines().skip(1).find_map(|line| {
+        let xs: Vec<_> = line.split_ascii_whitespace().collect();
+        let number: Decimal = xs.get(1)?.parse().ok()?;
+        let currency = xs.get(2)?;
+        (number.abs() > threshold).then(|| format!("{} {}", number, currency))
+    })
+}
+
+/// Symbols shown before the numbers of amounts in previews, sorted by their currencies
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "HashMap<String, String>")]
+pub struct CurrencySymbols(Vec<CurrencySymbol>);
+
+#[derive(Debug, Clone)]
+struct CurrencySymbol {
+    currency: String,
+    symbol: String,
+    /// Matches the amounts in `currency`, compiled once when the config is read
+    amount: Regex,
+}
+
+impl From<HashMap<String, String>> for CurrencySymbols {
+    fn from(symbols: HashMap<String, String>) -> Self {
+        let mut symbols: Vec<_> = symbols
+            .into_iter()
+            .map(|(currency, symbol)| {
+                let pattern = format!(r"(\s)(-?)([0-9][0-9.]*) {}(\s|$)", regex::escape(&currency));
+                CurrencySymbol {
+                    amount: Regex::new(&pattern).unwrap(),
+                    currency,
+                    symbol,
+                }
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.currency.cmp(&b.currency));
+        Self(symbols)
+    }
+}
+
+impl CurrencySymbols {
+    /// Returns the symbol of `currency`
+    pub fn get(&self, currency: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|s| s.currency == currency)
+            .map(|s| s.symbol.as_str())
+    }
+
+    /// Returns the currencies with symbols
+    pub fn currencies(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|s| s.currency.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Renders the amounts of the postings in a preview with the symbols of their currencies before
+/// the numbers, e.g. `¥10` for `10 CNY`
+pub fn localize_amounts(text: &str, symbols: &CurrencySymbols) -> String {
+    map_postings(text, |line| {
+        symbols.0.iter().fold(line.to_string(), |line, s| {
+            s.amount
+                .replace_all(&line, |caps: &Captures<'_>| {
+                    format!(
+                        "{}{}{}{}{}",
+                        &caps[1], &caps[2], s.symbol, &caps[3], &caps[4]
+                    )
+                })
+                .into_owned()
+        })
+    })
+}
+
+/// Renders the date of a preview in `format` instead of `YYYY-MM-DD`
+pub fn localize_date(text: &str, format: &str) -> String {
+    match transaction_date(text) {
+        Some(date) => format!("{}{}", date.format(format), &text[10..]),
+        None => text.to_string(),
+    }
+}
+
+/// Renders a preview for reading with the configured currency symbols and date format
+pub fn localize_preview(text: &str, config: &Beancount) -> String {
+    let text = localize_amounts(text, &config.currency_symbols);
+    match config.preview_date_format {
+        Some(ref format) => localize_date(&text, format),
+        None => text,
+    }
+}
+
+/// Maps the posting lines of a rendered transaction by `f`, leaving the other lines as they are
+fn map_postings(text: &str, f: impl Fn(&str) -> String) -> String {
+    text.split('\n')
+        .map(|line| {
+            let is_posting = line.starts_with(' ')
+                && line
+                    .split_ascii_whitespace()
+                    .next()
+                    .is_some_and(|x| !x.ends_with(':'));
+            if is_posting {
+                f(line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Adds `#tag` to the last occurrence of the rendered transaction `txn` in `content`. Returns the
+/// new content and the tagged transaction, or `None` if `txn` is not in `content`.
+pub fn add_transaction_tag(content: &str, txn: &str, tag: &str) -> Option<(String, String)> {
+    let txn = txn.trim_end();
+    let (start, _) = content.rmatch_indices(txn).find(|&(i, _)| {
+        let end = i + txn.len();
+        (i == 0 || content[..i].ends_with('\n'))
+            && (end == content.len() || content[end..].starts_with('\n'))
+    })?;
+    let header_end = txn.find('\n').unwrap_or(txn.len());
+    let tagged = format!("{} #{}{}", &txn[..header_end], tag, &txn[header_end..]);
+    let content = format!(
+        "{}{}{}",
+        &content[..start],
+        tagged,
+        &content[start + txn.len()..]
+    );
+    Some((content, tagged))
+}
+
+/// Returns the file transactions of `date` are placed in, i.e. `{root}/txs/{year}/{month}.bean`,
+/// or `{root}/txs/{year}/{month}/{day}.bean` for per-day files
+pub fn transaction_file(
+    root: impl AsRef<Path>,
+    date: NaiveDate,
+    granularity: Granularity,
+) -> PathBuf {
+    let year = root
+        .as_ref()
+        .join("txs")
+        .join(date.format("%Y").to_string());
+    match granularity {
+        Granularity::Month => year.join(date.format("%m.bean").to_string()),
+        Granularity::Day => year
+            .join(date.format("%m").to_string())
+            .join(date.format("%d.bean").to_string()),
+    }
+}
+
+/// Reads the transactions in the month of `month`. Per-day files are concatenated in the order of
+/// days. Missing files are treated as empty.
+pub fn read_month(
+    root: impl AsRef<Path>,
+    month: NaiveDate,
+    granularity: Granularity,
+) -> io::Result<Vec<u8>> {
+    let ignore_missing = |r: io::Result<Vec<u8>>| match r {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        r => r,
+    };
+    match granularity {
+        Granularity::Month => ignore_missing(fs::read(transaction_file(root, month, granularity))),
+        Granularity::Day => {
+            let dir = root
+                .as_ref()
+                .join("txs")
+                .join(month.format("%Y/%m").to_string());
+            let mut files = match fs::read_dir(dir) {
+                Ok(entries) => entries
+                    .map(|entry| entry.map(|e| e.path()))
+                    .collect::<io::Result<Vec<_>>>()?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            files.retain(|f| f.extension().is_some_and(|ext| ext == "bean"));
+            files.sort();
+            let mut content = Vec::new();
+            for file in files {
+                if !content.is_empty() {
+                    content.push(b'\n');
+                }
+                content.extend(fs::read(file)?);
+            }
+            Ok(content)
+        }
+    }
+}
+
+/// Returns the account named by `term` the way transactions resolve their accounts, including
+/// the configured numeric shortcuts
+pub fn resolve_account<'a>(
+    accounts: &'a [String],
+    term: &str,
+    config: &Beancount,
+) -> Result<&'a String> {
+    filter_numbered_account(accounts, term, config, |_| true).map(|(account, _)| account)
+}
+
+/// Sums the postings to `account` and its subaccounts per currency in the transactions dated from
+/// `from` to `to`, both inclusive. Missing files are treated as empty.
+pub fn account_delta(
+    root: impl AsRef<Path>,
+    granularity: Granularity,
+    account: &str,
+    sep: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> io::Result<BTreeMap<String, Decimal>> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut date = from;
+    while date <= to {
+        let file = transaction_file(&root, date, granularity);
+        if files.last() != Some(&file) {
+            files.push(file);
+        }
+        date = date.succ();
+    }
+    let sub = format!("{}{}", account, sep);
+    let mut totals = BTreeMap::new();
+    for file in files {
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut in_range = false;
+        for line in content.lines() {
+            if !line.starts_with(char::is_whitespace) {
+                in_range = transaction_date(line).is_some_and(|d| from <= d && d <= to);
+                continue;
+            }
+            let xs: Vec<_> = line.split_ascii_whitespace().collect();
+            if let [ac, number, currency, ..] = xs[..] {
+                if !in_range || (ac != account && !ac.starts_with(&sub)) {
+                    continue;
+                }
+                if let Ok(number) = number.parse::<Decimal>() {
+                    *totals.entry(currency.to_string()).or_default() += number;
+                }
+            }
+        }
+    }
+    Ok(totals)
+}
+
+/// Returns the date of a rendered transaction
+pub fn transaction_date(txn: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(txn.get(..10)?, "%F").ok()
+}
+
+/// Renders what appending `text` to a file with `existing` content would change as a unified diff,
+/// with up to three lines of context. `existing` is `None` if the file does not exist yet.
+pub fn append_diff(existing: Option<&str>, text: &str, path: &str, blank_lines: usize) -> String {
+    let old: Vec<_> = existing.map_or_else(Vec::new, |s| s.lines().collect());
+    let mut added = if old.is_empty() {
+        Vec::new()
+    } else {
+        vec![""; blank_lines]
+    };
+    added.extend(text.lines());
+    insertion_diff(existing.is_some(), &old, old.len(), &added, path)
+}
+
+/// Like [`append_diff`], but for inserting `text` before the existing transactions as
+/// [`prepend_to_file`] does
+pub fn prepend_diff(existing: Option<&str>, text: &str, path: &str, blank_lines: usize) -> String {
+    let old: Vec<_> = existing.map_or_else(Vec::new, |s| s.lines().collect());
+    match first_transaction_line(old.iter().copied()) {
+        Some(at) => {
+            let mut added: Vec<_> = text.lines().collect();
+            added.extend(vec![""; blank_lines]);
+            insertion_diff(true, &old, at, &added, path)
+        }
+        None => append_diff(existing, text, path, blank_lines),
+    }
+}
+
+/// Renders inserting the lines `added` before the line `at` of `old` as a unified diff, with up
+/// to three lines of context on each side
+fn insertion_diff(existed: bool, old: &[&str], at: usize, added: &[&str], path: &str) -> String {
+    let before = &old[at.saturating_sub(3)..at];
+    let after = &old[at..old.len().min(at + 3)];
+    let context = before.len() + after.len();
+    let old_start = at - before.len() + usize::from(context > 0);
+    let new_start = if context == 0 { 1 } else { old_start };
+    let mut diff = if existed {
+        format!("--- a/{}\n", path)
+    } else {
+        "--- /dev/null\n".to_string()
+    };
+    diff += &format!(
+        "+++ b/{}\n@@ -{},{} +{},{} @@\n",
+        path,
+        old_start,
+        context,
+        new_start,
+        context + added.len()
+    );
+    for line in before {
+        diff += &format!(" {}\n", line);
+    }
+    for line in added {
+        diff += &format!("+{}\n", line);
+    }
+    for line in after {
+        diff += &format!(" {}\n", line);
+    }
+    diff
+}
+
+/// Returns the index of the first line of the first transaction in `lines`, i.e. the first one
+/// starting with a date, which is after the header of options, includes and comments
+fn first_transaction_line<'a>(mut lines: impl Iterator<Item = &'a str>) -> Option<usize> {
+    lines.position(|line| transaction_date(line).is_some())
+}
+
+/// Inserts `text` into a file before its existing transactions, separated from them by
+/// `blank_lines` blank lines. Appends `text` if the file has no transactions, e.g. if it is empty
+/// or only has a header.
+pub fn prepend_to_file(
+    text: &str,
+    filename: impl AsRef<Path>,
+    blank_lines: usize,
+) -> io::Result<()> {
+    let content = match fs::read_to_string(&filename) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let lines: Vec<_> = content.split_inclusive('\n').collect();
+    let at = match first_transaction_line(lines.iter().copied()) {
+        Some(at) => at,
+        None => return append_to_file(text, filename, blank_lines),
+    };
+    let mut new = lines[..at].concat();
+    new += text;
+    new += &"\n".repeat(blank_lines + 1);
+    new += &lines[at..].concat();
+    fs::write(filename, new)
+}
+
+/// Appends `text` to a file, separated from the existing content by `blank_lines` blank lines
+pub fn append_to_file(
+    text: &str,
+    filename: impl AsRef<Path>,
+    blank_lines: usize,
+) -> io::Result<()> {
+    let parent = filename
+        .as_ref()
+        .parent()
+        .expect("there should be a parent");
+    if !parent.exists() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut fw = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(filename)?;
+    // have to seek end, otherwise the stream_position method will return 0
+    fw.seek(SeekFrom::End(0))?;
+    if fw.stream_position()? != 0 {
+        for _ in 0..blank_lines {
+            writeln!(fw)?;
+        }
+    }
+    writeln!(fw, "{}", text)?;
+    Ok(())
+}
+
+impl<'ac, 'am> Posting<'ac, 'am> {
+    pub fn new(account: &'ac str, amount: Amount<'am>) -> Self {
+        Self {
+            account,
+            amount,
+            price: None,
+        }
+    }
+}
+
+impl<'a> Amount<'a> {
+    pub fn from_str(s: &'a str, default_currency: &'a str) -> Option<Self> {
+        let (number, currency) = Self::parse(s)?;
+        Some(Self {
+            number,
+            currency: currency.unwrap_or(default_currency),
+        })
+    }
+
+    /// Parses the number and the optional currency of an amount. The currency can either follow
+    /// or precede the number, e.g. `10 USD` or `USD 10`.
+    fn parse(s: &'a str) -> Option<(Decimal, Option<&'a str>)> {
+        // the leading currency is lazy so that `USD10` is not taken as `USD1` and `0`
+        let regex = regex!(
+            r"^(?:([A-Z][A-Z0-9'._-]{0,22}?[A-Z0-9])\s*)?([+-]?[0-9.]+)\s*([A-Z][A-Z0-9'._-]{0,22}[A-Z0-9])?$"
+        );
+        let caps = regex.captures(s)?;
+        let number: Decimal = caps.get(2).and_then(|n| n.as_str().parse().ok())?;
+        let currency = match (caps.get(1), caps.get(3)) {
+            (Some(_), Some(_)) => return None,
+            (leading, trailing) => leading.or(trailing).map(|c| c.as_str()),
+        };
+        Some((number, currency))
+    }
+}
+
+impl<'a> std::ops::Neg for Amount<'a> {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Self {
+            number: -self.number,
+            currency: self.currency,
+        }
+    }
+}
+
+impl<'ac, 'am> Transaction<'ac, 'am> {
+    pub fn flag(&self) -> char {
+        self.flag
+    }
+
+    /// Renders the transaction for the preview message, followed by its notes
+    pub fn preview(&self) -> String {
+        if self.notes.is_empty() {
+            self.to_string()
+        } else {
+            format!("{}\n{}", self, self.notes.join("\n"))
+        }
+    }
+}
+
+/// Returns the transaction part of a preview message, without the notes after the first blank
+/// line
+pub fn preview_transaction(preview: &str) -> &str {
+    preview.split("\n\n").next().unwrap_or(preview)
+}
+
+// Displays
+impl<'ac, 'am> fmt::Display for Transaction<'ac, 'am> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // first line
+        write!(f, "{} ", self.date.format("%F"))?;
+        if self.txn_keyword && self.flag == FLAG_COMPLETE {
+            write!(f, "txn")?;
+        } else {
+            write!(f, "{}", self.flag)?;
+        }
+        if let Some(ref payee) = self.payee {
+            write!(f, r#" "{}""#, escape_string(payee))?;
+        }
+        write!(f, r#" "{}""#, escape_string(&self.narration))?;
+        for tag in self.tags.iter() {
+            write!(f, " {}", tag)?;
+        }
+        writeln!(f)?;
+
+        // metadata
+        for (key, value) in self.metadata.iter() {
+            writeln!(f, r#"    {}: "{}""#, key, escape_string(value))?;
+        }
+
+        // postings, with the accounts padded to the longest one plus a space if aligned
+        let width = if self.align_amounts {
+            let longest = self.postings.iter().map(|p| p.account.chars().count());
+            longest.max().unwrap_or(0) + 1
+        } else {
+            0
+        };
+        let mut postings: Vec<_> = self.postings.iter().collect();
+        match self.posting_order {
+            PostingOrder::Input => {}
+            PostingOrder::Debits => postings.sort_by_key(|p| p.amount.number.is_sign_negative()),
+            PostingOrder::Account => postings.sort_by_key(|p| p.account),
+        }
+        for posting in postings {
+            writeln!(f, "    {:width$}", posting, width = width)?;
+        }
+        // TODO: trim out the last \n
+        Ok(())
+    }
+}
+
+impl<'ac, 'am> fmt::Display for Posting<'ac, 'am> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the width pads the account to align the amounts
+        let width = f.width().unwrap_or(0);
+        write!(f, "{:<width$} {}", self.account, self.amount, width = width)?;
+        if let Some(ref price) = self.price {
+            write!(f, " @ {}", price)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Amount<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.number, self.currency)
+    }
+}
+
+/// Cached accounts of a beancount root, re-read when `accounts.bean` is modified
+#[derive(Debug, Default)]
+pub struct AccountCache {
+    accounts: Vec<String>,
+    mtime: Option<SystemTime>,
+}
+
+impl AccountCache {
+    /// Reads the accounts in `root`
+    pub fn read(root: impl AsRef<Path>) -> io::Result<Self> {
+        let mtime = fs::metadata(accounts_file(&root))?.modified()?;
+        Ok(Self {
+            accounts: get_accounts(root)?,
+            mtime: Some(mtime),
+        })
+    }
+
+    /// Returns the cached accounts if the accounts file in `root` has not changed since the last
+    /// read
+    pub fn fresh(&self, root: impl AsRef<Path>) -> io::Result<Option<&[String]>> {
+        let mtime = fs::metadata(accounts_file(&root))?.modified()?;
+        Ok(Some(self.accounts.as_slice()).filter(|_| self.mtime == Some(mtime)))
+    }
+
+    pub fn accounts(&self) -> &[String] {
+        &self.accounts
+    }
+}
+
+pub fn accounts_file(root: impl AsRef<Path>) -> PathBuf {
+    // TODO: categorize accounts to accounts/*.bean
+    // assuming all accounts are in {root}/accounts.bean
+    root.as_ref().join("accounts.bean")
+}
+
+/// Reads the opened accounts and the closed accounts in `root`
+fn read_accounts(path: impl AsRef<Path>) -> io::Result<(Vec<String>, HashSet<String>)> {
+    let account_path = BufReader::new(File::open(accounts_file(path))?);
+    let mut opened = Vec::new();
+    let mut closed = HashSet::new();
+    for (i, line) in account_path.lines().enumerate() {
+        let line = line?;
+        // files saved by some Windows editors start with a byte order mark
+        let line = match line.strip_prefix('\u{feff}') {
+            Some(stripped) if i == 0 => stripped,
+            _ => &line,
+        };
+        let xs = line
+            .split_ascii_whitespace()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        if xs.len() < 3 || xs[0].starts_with(';') {
+            continue;
+        }
+        match xs[1].as_str() {
+            "open" => {
+                // sadly, we have to clone here
+                //   https://users.rust-lang.org/t/why-cant-move-element-of-vector/30454/4
+                opened.push(xs[2].clone());
+            }
+            "close" => {
+                closed.insert(xs[2].clone());
+            }
+            _ => {}
+        }
+    }
+    Ok((opened, closed))
+}
+
+/// Returns the accounts in `root` that are opened and not closed
+pub fn get_accounts(path: impl AsRef<Path>) -> io::Result<Vec<String>> {
+    let (opened, closed) = read_accounts(path)?;
+    Ok(opened
+        .into_iter()
+        .filter(|ac| !closed.contains(ac))
+        .collect())
+}
+
+/// Checks the ledger of the main file `main` with the `bean-check` command `program`, returning
+/// the errors it reports if any
+pub fn bean_check(program: &str, main: &Path) -> Result<()> {
+    let out = match Command::new(program).arg(main).output() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            bail!(
+                "{} not found, install beancount or disable validate_before_commit",
+                program
+            )
+        }
+        out => out.with_context(|| format!("execution of {} failed", program))?,
+    };
+    ensure!(
+        out.status.success(),
+        "bean-check failed:\n{}{}",
+        String::from_utf8_lossy(&out.stdout),
+        String::from_utf8_lossy(&out.stderr).trim_end()
+    );
+    Ok(())
+}
+
+/// Returns the accounts of the postings in the rendered transaction `txn` that are not in
+/// `accounts`
+pub fn missing_accounts<'a>(txn: &'a str, accounts: &[String]) -> Vec<&'a str> {
+    txn.lines()
+        .skip(1)
+        .filter_map(|line| line.split_ascii_whitespace().next())
+        // skips metadata, whose keys start with a lowercase letter
+        .filter(|ac| ac.starts_with(|c: char| c.is_ascii_uppercase()))
+        .filter(|ac| !accounts.iter().any(|a| a == ac))
+        .collect()
+}
+
+/// Appends a `close` directive of `account` dated `date` to the accounts file in `root`
+pub fn close_account(root: impl AsRef<Path>, account: &str, date: NaiveDate) -> Result<()> {
+    let (opened, closed) = read_accounts(&root).context("get accounts failed")?;
+    ensure!(
+        opened.iter().any(|ac| ac == account),
+        "Account {} is not opened",
+        account
+    );
+    ensure!(
+        !closed.contains(account),
+        "Account {} is already closed",
+        account
+    );
+    let directive = format!("{} close {}", date.format("%F"), account);
+    append_to_file(&directive, accounts_file(root), 1).context("Append to file failed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::naive_today;
+    #[test]
+    fn test_matches() {
+        assert!(account_matches("Expenses:Transport:Public:Bus", "bus"));
+        assert!(account_matches("Expenses:Transport:Bus", "transp bus"));
+        assert!(account_matches("Expenses:Transport:Bus", " transp  bus "));
+    }
+
+    fn filter_accounts() -> Vec<String> {
+        vec![
+            "Assets:Cash:CNY",
+            "Assets:Cash:USD",
+            "Expenses:International:Fees",
+            "Expenses:Food:Groceries",
+            "Expenses:Health:Dental:Insurance",
+            "Expenses:Health:Life:GroupTermLife",
+            "Expenses:Health:Medical:Insurance",
+            "Expenses:Health:Vision:Insurance",
+            "Expenses:Home:Internet",
+            "Expenses:Home:Phone",
+            "Expenses:Home:Rent",
+            "Expenses:Tele:Mail",
+            "Expenses:Tele:Email",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+    }
+
+    #[test]
+    fn test_filter() {
+        let accounts = filter_accounts();
+        let pred = |s: &&String| s.starts_with("Expenses:");
+        assert!(format!(
+            "{}",
+            filter_account(&accounts, "insur", ":", MatchMode::Smart, pred).unwrap_err()
+        )
+        .starts_with("More than one last-component matched account: "));
+        assert!(format!(
+            "{}",
+            filter_account(&accounts, "insurance", ":", MatchMode::Smart, pred).unwrap_err()
+        )
+        .starts_with("More than one last-component exact-match account: "));
+        assert!(format!(
+            "{}",
+            filter_account(&accounts, "health", ":", MatchMode::Smart, pred).unwrap_err()
+        )
+        .starts_with("More than one matched account: "));
+        // whole account unique match
+        assert_eq!(
+            filter_account(&accounts, "dental", ":", MatchMode::Smart, pred)
+                .unwrap()
+                .0,
+            "Expenses:Health:Dental:Insurance"
+        );
+        // last component unique match
+        assert_eq!(
+            filter_account(&accounts, "inter", ":", MatchMode::Smart, pred)
+                .unwrap()
+                .0,
+            "Expenses:Home:Internet"
+        );
+        // last component unique exact match
+        assert_eq!(
+            filter_account(&accounts, "mail", ":", MatchMode::Smart, pred)
+                .unwrap()
+                .0,
+            "Expenses:Tele:Mail"
+        );
+        // multiple terms match
+        assert_eq!(
+            filter_account(&accounts, "med insur", ":", MatchMode::Smart, pred)
+                .unwrap()
+                .0,
+            "Expenses:Health:Medical:Insurance"
+        );
+    }
+
+    #[test]
+    fn test_match_mode() {
+        let accounts = filter_accounts();
+        let pred = |s: &&String| s.starts_with("Expenses:");
+        let filter = |term, mode| {
+            filter_account(&accounts, term, ":", mode, pred)
+                .map(|(ac, fuzzy)| (ac.as_str(), fuzzy))
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(
+            filter("inter", MatchMode::Smart),
+            Ok(("Expenses:Home:Internet", true))
+        );
+        assert!(filter("inter", MatchMode::Strict)
+            .unwrap_err()
+            .starts_with("More than one matched account: "));
+        assert_eq!(
+            filter("dental", MatchMode::Strict),
+            Ok(("Expenses:Health:Dental:Insurance", false))
+        );
+        assert_eq!(
+            filter("urance", MatchMode::Strict),
+            Err("More than one matched account: [\"Expenses:Health:Dental:Insurance\", \"Expenses:Health:Medical:Insurance\", \"Expenses:Health:Vision:Insurance\"]".to_string())
+        );
+
+        // "mail" is not the beginning of "Email"
+        assert_eq!(
+            filter("mail", MatchMode::Prefix),
+            Ok(("Expenses:Tele:Mail", false))
+        );
+        assert_eq!(
+            filter("home inter", MatchMode::Prefix),
+            Ok(("Expenses:Home:Internet", false))
+        );
+        assert!(filter("inter", MatchMode::Prefix)
+            .unwrap_err()
+            .starts_with("More than one matched account: "));
+        assert_eq!(
+            filter("urance", MatchMode::Prefix),
+            Err("No matched account".to_string())
+        );
+    }
+
+    fn config(extra: &str) -> Beancount {
+        toml::from_str(&format!(
+            "root = \"\"\ndefault_currency = \"CNY\"\n{}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn accounts() -> Vec<String> {
+        [
+            "Assets:Cash",
+            "Assets:Alipay",
+            "Liabilities:CreditCard",
+            "Expenses:Food",
+            "Expenses:Transport",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+    }
+
+    fn cmd(s: &str) -> Vec<String> {
+        crate::utils::command_split(s).unwrap()
+    }
+
+    /// Parses the transaction command `s` in the default currency `CNY` and renders it
+    fn parse_with(accounts: &[String], config: &Beancount, s: &str) -> Result<String> {
+        let cmds = cmd(s);
+        Transaction::today_from_command(&cmds, accounts, config, &ParseContext::new("CNY"))
+            .map(|txn| txn.to_string())
+    }
+
+    #[test]
+    fn test_max_length() {
+        let accounts = accounts();
+        let config = config("max_narration_length = 5\nmax_payee_length = 3");
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        assert!(parse("10 ali food abcd").is_ok());
+        assert!(parse("10 ali food abcde").is_ok());
+        assert!(parse("10 ali food 五个汉字啊").is_ok());
+        assert_eq!(
+            parse("10 ali food abcdef").unwrap_err().to_string(),
+            "Narration too long: 6 characters (maximum 5)"
+        );
+        assert!(parse(">ab 10 ali food").is_ok());
+        assert!(parse(">abc 10 ali food").is_ok());
+        assert_eq!(
+            parse(">abcd 10 ali food").unwrap_err().to_string(),
+            "Payee too long: 4 characters (maximum 3)"
+        );
+    }
+
+    #[test]
+    fn test_fallback_expense_account() {
+        let mut accounts = accounts();
+        let parse = |config: &Beancount, accounts: &[String], s: &str| {
+            let cmds = cmd(s);
+            Transaction::today_from_command(&cmds, accounts, config, &ParseContext::new("CNY"))
+                .map(|txn| txn.preview())
+                .map_err(|e| e.to_string())
+        };
+        let disabled = config("");
+        assert_eq!(
+            parse(&disabled, &accounts, "10 cash").unwrap_err(),
+            "Not enough arguments: expense account"
+        );
+
+        let config = config(r#"fallback_expense_account = "Expenses:Uncategorized""#);
+        assert_eq!(
+            parse(&config, &accounts, "10 cash").unwrap_err(),
+            "Fallback expense account Expenses:Uncategorized is not opened"
+        );
+        accounts.push("Expenses:Uncategorized".to_string());
+        assert_eq!(
+            parse(&config, &accounts, "10 cash").unwrap(),
+            format!(
+                "{} * \"\"\n    Expenses:Uncategorized 10 CNY\n    Assets:Cash -10 CNY\n\n\
+                 ℹ️ No expense account given, using Expenses:Uncategorized",
+                naive_today().format("%F")
+            )
+        );
+        // the expense account is used when given
+        assert!(parse(&config, &accounts, "10 cash food")
+            .unwrap()
+            .contains("Expenses:Food 10 CNY"));
+        // not for income
+        assert_eq!(
+            parse(&config, &accounts, "< 10 cash").unwrap_err(),
+            "Not enough arguments: account"
+        );
+    }
+
+    #[test]
+    fn test_keyword_tags() {
+        let accounts = accounts();
+        let config = config(
+            r##"
+            [keyword_tags]
+            uber = "rideshare"
+            airport = "#travel"
+            "Late Night" = "travel"
+            "##,
+        );
+        let parse = |s: &str| parse_with(&accounts, &config, s).unwrap();
+        let header = |rest: &str| format!("{} * {}\n", naive_today().format("%F"), rest);
+        assert!(
+            parse("10 ali transport 'Uber home'").starts_with(&header("\"Uber home\" #rideshare"))
+        );
+        assert!(parse("10 ali transport taxi home").starts_with(&header("\"taxi home\"")));
+        // the tags of several keywords are added once, after the explicit ones
+        assert!(
+            parse("#work 10 ali transport 'UBER to the airport, late night'").starts_with(&header(
+                "\"UBER to the airport, late night\" #work #rideshare #travel"
+            ))
+        );
+        assert!(parse("#travel 10 ali transport 'uber airport'")
+            .starts_with(&header("\"uber airport\" #travel #rideshare")));
+    }
+
+    #[test]
+    fn test_account_tags() {
+        let accounts = accounts();
+        let config = config(
+            r##"
+            [account_tags]
+            "Expenses:Trans" = "not-a-parent"
+            "Expenses:Transport" = "commute"
+            "Liabilities" = "#credit"
+            "##,
+        );
+        let parse = |s: &str| parse_with(&accounts, &config, s).unwrap();
+        let header = |rest: &str| format!("{} * {}\n", naive_today().format("%F"), rest);
+        assert!(parse("10 ali transport taxi").starts_with(&header("\"taxi\" #commute")));
+        assert!(parse("#commute 10 card transport taxi")
+            .starts_with(&header("\"taxi\" #commute #credit")));
+        assert!(parse("10 ali food lunch").starts_with(&header("\"lunch\"")));
+    }
+
+    #[test]
+    fn test_default_narration() {
+        let accounts = accounts();
+        let config = config(
+            r#"
+            require_narration = true
+            [narrations]
+            "Expenses:Transport" = "Commute"
+            "#,
+        );
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        let header =
+            |narration: &str| format!("{} * \"{}\"\n", naive_today().format("%F"), narration);
+        assert!(parse("10 ali transport")
+            .unwrap()
+            .starts_with(&header("Commute")));
+        assert!(parse("10 ali transport taxi home")
+            .unwrap()
+            .starts_with(&header("taxi home")));
+        assert!(parse("10 ali 4 transport _ food")
+            .unwrap()
+            .starts_with(&header("Commute")));
+        assert_eq!(
+            parse("10 ali food").unwrap_err().to_string(),
+            "Empty narration"
+        );
+    }
+
+    #[test]
+    fn test_payee_narration() {
+        let accounts = accounts();
+        let parse = |config: &Beancount, s: &str| {
+            parse_with(&accounts, config, s).map_err(|e| e.to_string())
+        };
+        let header = |rest: &str| format!("{} * {}\n", naive_today().format("%F"), rest);
+        let config = config("payee_narration = \"{payee}\"\nrequire_narration = true");
+        assert!(parse(&config, ">'Pizza Hut' 10 ali food")
+            .unwrap()
+            .starts_with(&header("\"Pizza Hut\" \"Pizza Hut\"")));
+        assert!(parse(&config, ">'Pizza Hut' 10 ali food lunch")
+            .unwrap()
+            .starts_with(&header("\"Pizza Hut\" \"lunch\"")));
+        // still empty without a payee
+        assert_eq!(
+            parse(&config, "10 ali food").unwrap_err(),
+            "Empty narration"
+        );
+        let config = self::config("payee_narration = \"Paid to {payee}\"");
+        assert!(parse(&config, ">'Pizza Hut' 10 ali food")
+            .unwrap()
+            .starts_with(&header("\"Pizza Hut\" \"Paid to Pizza Hut\"")));
+        // the narration is kept empty by default
+        assert!(parse(&self::config(""), ">'Pizza Hut' 10 ali food")
+            .unwrap()
+            .starts_with(&header("\"Pizza Hut\" \"\"")));
+    }
+
+    #[test]
+    fn test_swap_payee_narration() {
+        let accounts = accounts();
+        let parse = |config: &Beancount| {
+            parse_with(&accounts, config, ">'lunch with Bob' 10 ali food Pizza Hut").unwrap()
+        };
+        let today = naive_today().format("%F");
+        let postings = "    Expenses:Food 10 CNY\n    Assets:Alipay -10 CNY\n";
+        assert_eq!(
+            parse(&config("")),
+            format!("{} * \"lunch with Bob\" \"Pizza Hut\"\n{}", today, postings)
+        );
+        let swapped = config("swap_payee_narration = true");
+        assert_eq!(
+            parse(&swapped),
+            format!("{} * \"Pizza Hut\" \"lunch with Bob\"\n{}", today, postings)
+        );
+        let txn = parse_with(&accounts, &swapped, "10 ali food").unwrap();
+        assert_eq!(txn, format!("{} * \"\"\n{}", today, postings));
+    }
+
+    #[test]
+    fn test_localize_amounts() {
+        let accounts = accounts();
+        let ctx = ParseContext::new("CNY");
+        let symbols: HashMap<_, _> = [("CNY", "¥"), ("USD", "$")]
+            .iter()
+            .map(|(c, s)| (c.to_string(), s.to_string()))
+            .collect();
+        let symbols = CurrencySymbols::from(symbols);
+        let cmds = cmd("'100 USD' card '710 CNY' food @ 7.1 dinner");
+        let txn = Transaction::today_from_command(&cmds, &accounts, &config(""), &ctx).unwrap();
+        let committed = txn.to_string();
+        let preview = localize_amounts(&txn.preview(), &symbols);
+        assert_eq!(
+            preview,
+            format!(
+                "{} * \"dinner\"\n    Expenses:Food ¥710\n    Liabilities:CreditCard -$100 @ ¥7.1\n",
+                naive_today().format("%F")
+            )
+        );
+        assert_ne!(preview, committed);
+
+        // unconfigured currencies and the other lines are left as they are
+        let txn = "2021-03-05 * \"10 CNY\"\n    note: \"10 CNY\"\n    Expenses:Food 10 EUR\n    Assets:Cash -10 EUR";
+        assert_eq!(localize_amounts(txn, &symbols), txn);
+        assert_eq!(symbols.get("USD"), Some("$"));
+        assert_eq!(symbols.get("EUR"), None);
+    }
+
+    #[test]
+    fn test_separate_currency() {
+        let mut accounts = accounts();
+        accounts.push("Assets:Bank:HSBC".to_string());
+        let config = config("");
+        let parse = |s: &str| parse_with(&accounts, &config, s).map_err(|e| format!("{:#}", e));
+        let today = naive_today().format("%F");
+        let usd = format!(
+            "{} * \"lunch\"\n    Expenses:Food 10 USD\n    Assets:Alipay -10 USD\n",
+            today
+        );
+        assert_eq!(parse("10 USD ali food lunch").unwrap(), usd);
+        assert_eq!(parse("10USD ali food lunch").unwrap(), usd);
+        let cny = usd.replace("USD", "CNY");
+        assert_eq!(parse("10 CNY ali food lunch").unwrap(), cny);
+        assert_eq!(parse("10CNY ali food lunch").unwrap(), cny);
+        assert_eq!(parse("10 ali food lunch").unwrap(), cny);
+        // an account looking like a currency
+        assert_eq!(
+            parse("10 HSBC food lunch").unwrap(),
+            format!(
+                "{} * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Bank:HSBC -10 CNY\n",
+                today
+            )
+        );
+        // a currency after an amount with one is an account
+        assert_eq!(
+            parse("10USD USD food lunch").unwrap_err(),
+            "Invalid spend account: No matched account"
+        );
+    }
+
+    #[test]
+    fn test_preview_date_format() {
+        let accounts = accounts();
+        let ctx = ParseContext::new("CNY");
+        let cmds = cmd("2021-03-05 10 cash food lunch");
+        for format in ["%Y年%-m月%-d日", "%a %d %b %Y"] {
+            let config = config(&format!("preview_date_format = {:?}", format));
+            let txn = Transaction::today_from_command(&cmds, &accounts, &config, &ctx).unwrap();
+            let committed = txn.to_string();
+            assert!(committed.starts_with("2021-03-05 * "));
+            let preview = localize_preview(&txn.preview(), &config);
+            assert_ne!(preview, committed);
+        }
+        let config = config(r#"preview_date_format = "%Y年%-m月%-d日""#);
+        let txn = Transaction::today_from_command(&cmds, &accounts, &config, &ctx).unwrap();
+        assert!(localize_preview(&txn.preview(), &config).starts_with("2021年3月5日 * \"lunch\"\n"));
+        let config = self::config(r#"preview_date_format = "%a %d %b %Y""#);
+        assert!(
+            localize_preview(&txn.preview(), &config).starts_with("Fri 05 Mar 2021 * \"lunch\"\n")
+        );
+    }
+
+    #[test]
+    fn test_account_numbers() {
+        let accounts = accounts();
+        let config = config(
+            r#"[account_numbers]
+            1 = "Assets:Cash"
+            2 = "Expenses:Food"
+            3 = "Expenses:Rent""#,
+        );
+        let parse = |s: &str| parse_with(&accounts, &config, s).map_err(|e| format!("{:#}", e));
+        let today = naive_today().format("%F");
+        assert_eq!(
+            parse("10 1 2 lunch").unwrap(),
+            format!(
+                "{} * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY\n",
+                today
+            )
+        );
+        assert_eq!(parse("10 1 2 lunch"), parse("10 cash food lunch"));
+        // other numbers are still split amounts
+        assert_eq!(
+            parse("10 4 1 _ ali 2 lunch").unwrap(),
+            format!(
+                "{} * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -4 CNY\n    Assets:Alipay -6 CNY\n",
+                today
+            )
+        );
+        assert_eq!(
+            parse("10 2 1 lunch").unwrap_err(),
+            "Spend account must be an asset or a liability, not an expense: Expenses:Food"
+        );
+        assert_eq!(
+            parse("10 1 3 rent").unwrap_err(),
+            "Invalid expense account: Account Expenses:Rent of shortcut 3 is not opened"
+        );
+    }
+
+    #[test]
+    fn test_surrounding_spaces() {
+        let accounts = accounts();
+        let parse = |config: &Beancount, s: &str| {
+            let txn = parse_with(&accounts, config, s).unwrap();
+            txn.lines().next().unwrap()[11..].to_string()
+        };
+        let trimmed = config("");
+        assert_eq!(
+            parse(&trimmed, "'> Pizza  Hut ' 10 ali food ' lunch  with Bob '"),
+            r#"* "Pizza  Hut" "lunch  with Bob""#
+        );
+        assert_eq!(
+            parse(&trimmed, "'>  ' 10 ali food ' lunch'"),
+            r#"* "lunch""#
+        );
+        let kept = config("keep_surrounding_spaces = true");
+        assert_eq!(
+            parse(&kept, "'> Pizza  Hut ' 10 ali food ' lunch  with Bob '"),
+            r#"* " Pizza  Hut " " lunch  with Bob ""#
+        );
+    }
+
+    #[test]
+    fn test_require_narration() {
+        let accounts = accounts();
+        let parse = |config: &Beancount, s: &str| parse_with(&accounts, config, s);
+        let required = config("require_narration = true");
+        assert_eq!(
+            parse(&required, "10 ali food").unwrap_err().to_string(),
+            "Empty narration"
+        );
+        assert!(parse(&required, "10 ali food lunch").is_ok());
+        assert!(parse(&config(""), "10 ali food").is_ok());
+    }
+
+    #[test]
+    fn test_fuzzy_flag() {
+        let accounts: Vec<_> = [
+            "Assets:Cash",
+            "Expenses:Food",
+            "Expenses:Food:Fruit",
+            "Expenses:Food:Snack",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        let config = config("");
+        let cmds = cmd("10 cash snack");
+        let txn =
+            Transaction::today_from_command(&cmds, &accounts, &config, &ParseContext::new("CNY"))
+                .unwrap();
+        assert_eq!(txn.flag, FLAG_COMPLETE);
+        // "food" matches all the expense accounts, "Expenses:Food" is picked by its last component
+        let cmds = cmd("10 cash food");
+        let txn =
+            Transaction::today_from_command(&cmds, &accounts, &config, &ParseContext::new("CNY"))
+                .unwrap();
+        assert_eq!(txn.flag, FLAG_INCOMPLETE);
+
+        let text = txn.to_string();
+        assert_eq!(transaction_flag(&text), Some(FLAG_INCOMPLETE));
+        let approved = set_transaction_flag(&text, FLAG_COMPLETE).unwrap();
+        assert_eq!(transaction_flag(&approved), Some(FLAG_COMPLETE));
+        assert_eq!(approved.replacen('*', "!", 1), text);
+    }
+
+    #[test]
+    fn test_txn_keyword() {
+        let accounts = accounts();
+        let render =
+            |config: &Beancount| parse_with(&accounts, config, "10 cash food lunch").unwrap();
+        let today = naive_today().format("%F");
+        let postings = "    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY\n";
+        let txn = render(&config(""));
+        assert_eq!(txn, format!("{} * \"lunch\"\n{}", today, postings));
+        let txn = render(&config("txn_keyword = true"));
+        assert_eq!(txn, format!("{} txn \"lunch\"\n{}", today, postings));
+        assert_eq!(transaction_flag(&txn), Some(FLAG_COMPLETE));
+
+        // incomplete transactions keep their flag
+        let mut accounts = accounts;
+        accounts.push("Expenses:Food:Fruit".to_string());
+        let txn = parse_with(
+            &accounts,
+            &config("txn_keyword = true"),
+            "10 cash food lunch",
+        )
+        .unwrap();
+        assert_eq!(transaction_flag(&txn), Some(FLAG_INCOMPLETE));
+    }
+
+    #[test]
+    fn test_entered_by() {
+        let accounts = accounts();
+        let cmds = cmd("10 ali food lunch");
+        let parse = |config: &Beancount, user| {
+            let ctx = ParseContext {
+                user,
+                ..ParseContext::new("CNY")
+            };
+            Transaction::today_from_command(&cmds, &accounts, config, &ctx)
+                .unwrap()
+                .to_string()
+        };
+
+        let disabled = config("");
+        assert!(!parse(&disabled, Some("alice")).contains("entered-by"));
+
+        let enabled = config("entered_by = true");
+        let txn = parse(&enabled, Some("alice"));
+        let lines: Vec<_> = txn.lines().collect();
+        assert_eq!(lines[1], r#"    entered-by: "alice""#);
+        assert_eq!(lines[2], "    Expenses:Food 10 CNY");
+        assert!(!parse(&enabled, None).contains("entered-by"));
+    }
+
+    #[test]
+    fn test_align_amounts() {
+        let accounts = accounts();
+        let config = config("align_amounts = true");
+        let txn = parse_with(
+            &accounts,
+            &config,
+            "'100 CNY' card 60 food _ transport taxi",
+        )
+        .unwrap();
+        let postings: Vec<_> = txn.lines().skip(1).collect();
+        assert_eq!(
+            postings,
+            [
+                "    Expenses:Food           60 CNY",
+                "    Expenses:Transport      40 CNY",
+                "    Liabilities:CreditCard  -100 CNY",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_future_days() {
+        let accounts = accounts();
+        let parse = |config: &Beancount, date: chrono::NaiveDate| {
+            let s = format!("{} 10 cash food lunch", date.format("%F"));
+            parse_with(&accounts, config, &s).map_err(|e| e.to_string())
+        };
+        let today = naive_today();
+        let config = config("max_future_days = 7");
+        assert!(parse(&config, today + chrono::Duration::days(7)).is_ok());
+        let far = today + chrono::Duration::days(8);
+        assert_eq!(
+            parse(&config, far).unwrap_err(),
+            format!("Date {} is more than 7 days in the future", far)
+        );
+        // past dates and disabled checks are fine
+        assert!(parse(&config, today - chrono::Duration::days(365)).is_ok());
+        assert!(parse(&self::config(""), far).is_ok());
+    }
+
+    #[test]
+    fn test_verbatim_narration() {
+        let accounts = accounts();
+        let config = config("");
+        let parse = |s: &str| {
+            let txn = parse_with(&accounts, &config, s).unwrap();
+            txn.lines().next().unwrap()[11..].to_string()
+        };
+        assert_eq!(
+            parse("10 cash food 2 cash burgers"),
+            r#"* "2 cash burgers""#
+        );
+        assert_eq!(parse("10 cash food 5USD | ali"), r#"* "5USD | ali""#);
+        assert_eq!(parse("10 cash food @ home"), r#"* "@ home""#);
+        assert_eq!(
+            parse("'100 USD' card '710 CNY' food @ 7.1 @ 7 eleven"),
+            r#"* "@ 7 eleven""#
+        );
+    }
+
+    #[test]
+    fn test_posting_order() {
+        let accounts = accounts();
+        let postings = |order: &str, s: &str| {
+            let config = config(&format!("posting_order = {:?}", order));
+            let txn = parse_with(&accounts, &config, s).unwrap();
+            txn.lines()
+                .skip(1)
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        let split = "100 70 ali _ card | 60 transport _ food taxi";
+        assert_eq!(
+            postings("input", split),
+            [
+                "    Expenses:Transport 60 CNY",
+                "    Expenses:Food 40 CNY",
+                "    Assets:Alipay -70 CNY",
+                "    Liabilities:CreditCard -30 CNY",
+            ]
+        );
+        assert_eq!(postings("debits", split), postings("input", split));
+        assert_eq!(
+            postings("account", split),
+            [
+                "    Assets:Alipay -70 CNY",
+                "    Expenses:Food 40 CNY",
+                "    Expenses:Transport 60 CNY",
+                "    Liabilities:CreditCard -30 CNY",
+            ]
+        );
+
+        let refund = "-10 ali food refund";
+        assert_eq!(
+            postings("input", refund),
+            ["    Expenses:Food -10 CNY", "    Assets:Alipay 10 CNY"]
+        );
+        assert_eq!(
+            postings("debits", refund),
+            ["    Assets:Alipay 10 CNY", "    Expenses:Food -10 CNY"]
+        );
+    }
+
+    #[test]
+    fn test_expense_as_spend() {
+        let accounts = accounts();
+        let config = config("");
+        let parse = |s: &str| parse_with(&accounts, &config, s).map_err(|e| e.to_string());
+        assert_eq!(
+            parse("10 food transport taxi").unwrap_err(),
+            "Spend account must be an asset or a liability, not an expense: Expenses:Food"
+        );
+        assert_eq!(
+            parse("10 unknown food").unwrap_err(),
+            "Invalid spend account"
+        );
+        assert!(parse("10 cash food").is_ok());
+    }
+
+    #[test]
+    fn test_from_previous() {
+        let accounts = accounts();
+        let config = config("entered_by = true");
+        let ctx = ParseContext {
+            user: Some("bob"),
+            ..ParseContext::new("CNY")
+        };
+        let today = naive_today().format("%F");
+        let reuse = |prev: &str, amount: &str| {
+            Transaction::from_previous(prev, amount, &accounts, &config, &ctx)
+                .map(|txn| txn.to_string())
+                .map_err(|e| e.to_string())
+        };
+        let prev = "2021-03-05 * \"Shop \\\"A\\\"\" \"lunch out\" #work #trip\n    entered-by: \"alice\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY\n\n已提交✅";
+        assert_eq!(
+            reuse(prev, "12.5").unwrap(),
+            format!(
+                "{} * \"Shop \\\"A\\\"\" \"lunch out\" #work #trip\n    entered-by: \"bob\"\n    Expenses:Food 12.5 CNY\n    Assets:Cash -12.5 CNY\n",
+                today
+            )
+        );
+        let prev = "2021-03-05 * \"lunch\"\n    Expenses:Food -10 CNY\n    Assets:Cash 10 CNY";
+        assert_eq!(
+            reuse(prev, "'20 USD'").unwrap_err(),
+            "Invalid amount '20 USD'"
+        );
+        assert_eq!(
+            reuse(prev, "20 USD").unwrap(),
+            format!(
+                "{} * \"lunch\"\n    entered-by: \"bob\"\n    Expenses:Food -20 USD\n    Assets:Cash 20 USD\n",
+                today
+            )
+        );
+        assert_eq!(
+            reuse("hello", "10").unwrap_err(),
+            "The replied message is not a transaction"
+        );
+        assert_eq!(
+            reuse(
+                "2021-03-05 * \"a\"\n    Expenses:Rent 10 CNY\n    Assets:Cash -10 CNY",
+                "10"
+            )
+            .unwrap_err(),
+            "Account Expenses:Rent is not opened"
+        );
+        assert_eq!(
+            reuse("2021-03-05 * \"a\"\n    Expenses:Food 6 CNY\n    Expenses:Transport 4 CNY\n    Assets:Cash -10 CNY", "10")
+                .unwrap_err(),
+            "Only transactions of two postings without prices can be reused"
+        );
+
+        // narrations starting with `#` are not tags
+        let prev =
+            "2021-03-05 ! \"#1 coffee\" #work\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY";
+        assert_eq!(
+            reuse(prev, "12").unwrap(),
+            format!(
+                "{} ! \"#1 coffee\" #work\n    entered-by: \"bob\"\n    Expenses:Food 12 CNY\n    Assets:Cash -12 CNY\n",
+                today
+            )
+        );
+        let config = self::config("max_narration_length = 5\nmax_postings = 2");
+        assert_eq!(
+            Transaction::from_previous(prev, "12", &accounts, &config, &ctx)
+                .unwrap_err()
+                .to_string(),
+            "Narration too long: 9 characters (maximum 5)"
+        );
+        let config = self::config("max_postings = 1");
+        assert_eq!(
+            Transaction::from_previous(prev, "12", &accounts, &config, &ctx)
+                .unwrap_err()
+                .to_string(),
+            "Too many postings: 2 (maximum 1)"
+        );
+    }
+
+    #[test]
+    fn test_leading_date() {
+        let accounts = accounts();
+        let config = config("");
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        assert_eq!(
+            parse("2021-03-05 >Shop 10 cash food lunch").unwrap(),
+            "2021-03-05 * \"Shop\" \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY\n"
+        );
+        let year = naive_today().format("%Y");
+        assert_eq!(
+            parse("03-05 10 cash food 2021-01-01").unwrap(),
+            format!(
+                "{}-03-05 * \"2021-01-01\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY\n",
+                year
+            )
+        );
+        // not dates
+        assert!(parse("-10 cash food")
+            .unwrap()
+            .starts_with(&naive_today().format("%F").to_string()));
+        assert_eq!(
+            parse("02-30 10 cash food").unwrap_err().to_string(),
+            "Invalid amount 02-30"
+        );
+    }
+
+    #[test]
+    fn test_amount_above() {
+        let accounts = accounts();
+        let config = config("entered_by = true");
+        let ctx = ParseContext {
+            user: Some("alice"),
+            ..ParseContext::new("CNY")
+        };
+        let parse = |s| {
+            Transaction::today_from_command(&cmd(s), &accounts, &config, &ctx)
+                .unwrap()
+                .to_string()
+        };
+        let threshold = Decimal::from(1000);
+        let txn = parse("10000 ali food lunch");
+        assert_eq!(amount_above(&txn, threshold).unwrap(), "10000 CNY");
+        assert_eq!(amount_above(&parse("1000 ali food lunch"), threshold), None);
+        assert_eq!(
+            amount_above(&parse("100.00 ali food lunch"), threshold),
+            None
+        );
+        // only the spend posting is above the threshold
+        let txn = parse("1500 card 600 food 900 transport");
+        assert_eq!(amount_above(&txn, threshold).unwrap(), "-1500 CNY");
+        let txn = parse("'1200 USD' ali food");
+        assert_eq!(amount_above(&txn, threshold).unwrap(), "1200 USD");
+    }
+
+    #[test]
+    fn test_amount_from_str() {
+        let parse =
+            |s| Amount::from_str(s, "CNY").map(|a| (a.number.to_string(), a.currency.to_string()));
+        let amount = |n: &str, c: &str| Some((n.to_string(), c.to_string()));
+        assert_eq!(parse("10 USD"), amount("10", "USD"));
+        assert_eq!(parse("10USD"), amount("10", "USD"));
+        assert_eq!(parse("USD 10"), amount("10", "USD"));
+        assert_eq!(parse("USD10"), amount("10", "USD"));
+        assert_eq!(parse("USD -10.5"), amount("-10.5", "USD"));
+        assert_eq!(parse("10"), amount("10", "CNY"));
+        assert_eq!(parse("VBTI 3"), amount("3", "VBTI"));
+        assert_eq!(parse("USD 10 USD"), None);
+        assert_eq!(parse("USD"), None);
+        assert_eq!(parse("cash"), None);
+        assert_eq!(parse("usd 10"), None);
+
+        // a leading currency is still an amount in a command
+        let accounts = accounts();
+        let txn = parse_with(&accounts, &config(""), "'USD 10' cash food lunch").unwrap();
+        assert!(txn.contains("    Expenses:Food 10 USD\n"));
+    }
+
+    #[test]
+    fn test_round_postings() {
+        let precision: HashMap<_, _> = vec![("CNY".to_string(), 2)].into_iter().collect();
+        let third = Decimal::from(10) / Decimal::from(3);
+        let amount = |number, currency| Amount { number, currency };
+        let mut postings = vec![
+            Posting::new("Expenses:Food", amount(third, "CNY")),
+            Posting::new("Expenses:Drink", amount(third, "CNY")),
+            Posting::new("Expenses:Fruit", amount(third, "CNY")),
+            Posting::new("Expenses:Transport", amount(third, "USD")),
+            Posting::new("Assets:Cash", amount(Decimal::from(-10), "CNY")),
+        ];
+        round_postings(&mut postings, &precision, Rounding::HalfUp);
+        let numbers: Vec<_> = postings
+            .iter()
+            .map(|p| p.amount.number.to_string())
+            .collect();
+        assert_eq!(
+            numbers,
+            ["3.34", "3.33", "3.33", third.to_string().as_str(), "-10"]
+        );
+
+        let mut postings = vec![
+            Posting::new("Expenses:Food", amount("10.005".parse().unwrap(), "CNY")),
+            Posting::new("Assets:Cash", amount("-10.005".parse().unwrap(), "CNY")),
+        ];
+        round_postings(&mut postings, &precision, Rounding::HalfUp);
+        assert_eq!(postings[0].amount.number.to_string(), "10.01");
+        assert_eq!(postings[1].amount.number.to_string(), "-10.01");
+    }
+
+    #[test]
+    fn test_rounding() {
+        let precision: HashMap<_, _> = vec![("CNY".to_string(), 2)].into_iter().collect();
+        let rounded = |number: &str, rounding| {
+            let number = number.parse().unwrap();
+            let mut postings = [
+                Posting::new(
+                    "Expenses:Food",
+                    Amount {
+                        number,
+                        currency: "CNY",
+                    },
+                ),
+                Posting::new(
+                    "Assets:Cash",
+                    Amount {
+                        number: -number,
+                        currency: "CNY",
+                    },
+                ),
+            ];
+            round_postings(&mut postings, &precision, rounding);
+            postings
+                .iter()
+                .map(|p| p.amount.number.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rounded("0.125", Rounding::HalfUp), ["0.13", "-0.13"]);
+        assert_eq!(rounded("0.125", Rounding::HalfEven), ["0.12", "-0.12"]);
+        assert_eq!(rounded("0.135", Rounding::HalfUp), ["0.14", "-0.14"]);
+        assert_eq!(rounded("0.135", Rounding::HalfEven), ["0.14", "-0.14"]);
+        assert_eq!(rounded("2.5", Rounding::HalfEven), ["2.5", "-2.5"]);
+        assert_eq!(rounded("0.1251", Rounding::HalfEven), ["0.13", "-0.13"]);
+    }
+
+    #[test]
+    fn test_expand_shortcut() {
+        let config = config(
+            r#"
+            [shortcuts]
+            coffee = { account = "card", expense = "Expenses:Food:Coffee" }
+            food = { account = "cash", expense = "food" }
+            "#,
+        );
+        let expand = |s: &str| {
+            let mut cmds = cmd(s);
+            expand_shortcut(&mut cmds, &config.shortcuts);
+            cmds
+        };
+        assert_eq!(expand("coffee 18"), ["18", "card", "Expenses:Food:Coffee"]);
+        assert_eq!(
+            expand(">Starbucks #work Coffee 18 latte"),
+            [
+                ">Starbucks",
+                "#work",
+                "18",
+                "card",
+                "Expenses:Food:Coffee",
+                "latte"
+            ]
+        );
+        // a keyword without an amount is left alone
+        assert_eq!(expand("coffee"), ["coffee"]);
+        // "food" is also an account term, but it is only a keyword at the amount position
+        assert_eq!(expand("food 10 lunch"), ["10", "cash", "food", "lunch"]);
+        assert_eq!(expand("10 ali food lunch"), ["10", "ali", "food", "lunch"]);
+    }
+
+    #[test]
+    fn test_opening_balance() {
+        let mut accounts = accounts();
+        let config = config("");
+        let parse = |accounts: &[String], s: &str| {
+            let cmds = cmd(s);
+            Transaction::opening_balance_from_command(&cmds, accounts, "CNY", &config)
+                .map(|t| t.to_string())
+        };
+        assert_eq!(
+            parse(&accounts, "cash 1000").unwrap_err().to_string(),
+            "Opening balances account Equity:Opening-Balances is not opened"
+        );
+
+        accounts.push("Equity:Opening-Balances".to_string());
+        let expected = format!(
+            "{} * \"Opening balance\"\n    Assets:Cash 1000 USD\n    Equity:Opening-Balances -1000 USD\n",
+            naive_today().format("%F")
+        );
+        assert_eq!(parse(&accounts, "assets cash 1000 USD").unwrap(), expected);
+        assert_eq!(parse(&accounts, "cash '1000 USD'").unwrap(), expected);
+        assert_eq!(parse(&accounts, "cash 1000USD").unwrap(), expected);
+        assert!(parse(&accounts, "credit 1000")
+            .unwrap()
+            .contains("    Liabilities:CreditCard 1000 CNY\n"));
+
+        assert_eq!(
+            parse(&accounts, "bank 1000").unwrap_err().to_string(),
+            "Invalid asset account"
+        );
+        // expense accounts are not allowed
+        assert_eq!(
+            parse(&accounts, "food 1000").unwrap_err().to_string(),
+            "Invalid asset account"
+        );
+        assert_eq!(
+            parse(&accounts, "1000").unwrap_err().to_string(),
+            "Not enough arguments: account"
+        );
+        assert_eq!(
+            parse(&accounts, "cash").unwrap_err().to_string(),
+            "Not enough arguments: amount"
+        );
+    }
+
+    #[test]
+    fn test_default_payee() {
+        let accounts = accounts();
+        let config = config("");
+        let ctx = ParseContext {
+            default_payee: Some("Starbucks"),
+            ..ParseContext::new("CNY")
+        };
+        let parse = |s: &str| {
+            let cmds = cmd(s);
+            let txn = Transaction::today_from_command(&cmds, &accounts, &config, &ctx).unwrap();
+            txn.to_string().lines().next().unwrap()[11..].to_string()
+        };
+        assert_eq!(parse("30 ali food latte"), r#"* "Starbucks" "latte""#);
+        assert_eq!(parse(">Costa 30 ali food latte"), r#"* "Costa" "latte""#);
+    }
+
+    #[test]
+    fn test_mark_default_currency() {
+        let accounts = accounts();
+        let ctx = ParseContext::new("CNY");
+        let preview = |config: &Beancount, s: &str| {
+            let cmds = cmd(s);
+            Transaction::today_from_command(&cmds, &accounts, config, &ctx)
+                .unwrap()
+                .preview()
+        };
+        let note = "ℹ️ No currency given, using CNY";
+        let config = config("mark_default_currency = true");
+        let defaulted = preview(&config, "10 cash food lunch");
+        assert!(defaulted.ends_with(&format!("\n\n{}", note)));
+        assert!(!preview_transaction(&defaulted).contains(note));
+        assert!(!preview(&config, "10USD cash food lunch").contains(note));
+        assert!(!preview(&self::config(""), "10 cash food lunch").contains(note));
+    }
+
+    #[test]
+    fn test_conversion_note() {
+        let accounts = accounts();
+        let ctx = ParseContext::new("CNY");
+        let preview = |config: &Beancount, s: &str| {
+            let cmds = cmd(s);
+            Transaction::today_from_command(&cmds, &accounts, config, &ctx)
+                .unwrap()
+                .preview()
+        };
+        let config = config(r#"rates = { USD = "7.2", JPY = 0.048 }"#);
+        let usd = preview(&config, "12.5USD cash food lunch");
+        assert!(usd.ends_with("\n\nℹ️ ≈ 90.00 CNY at 7.2 CNY/USD"));
+        assert!(!preview_transaction(&usd).contains("ℹ️"));
+        assert!(preview(&config, "1234JPY cash food lunch")
+            .ends_with("\n\nℹ️ ≈ 59.23 CNY at 0.048 CNY/JPY"));
+        // no rates for the default currency and unlisted currencies
+        assert!(!preview(&config, "10 cash food lunch").contains("ℹ️"));
+        assert!(!preview(&config, "10EUR cash food lunch").contains("ℹ️"));
+        // prices are committed as they are
+        assert!(!preview(&config, "10USD cash food @ 7CNY lunch").contains("ℹ️"));
+    }
+
+    #[test]
+    fn test_totals_note() {
+        let accounts = accounts();
+        let ctx = ParseContext::new("CNY");
+        let config = config("preview_totals = true");
+        let preview = |s: &str| {
+            let cmds = cmd(s);
+            Transaction::today_from_command(&cmds, &accounts, &config, &ctx)
+                .unwrap()
+                .preview()
+        };
+        let split = preview("100 50 cash _ card | 50.5 food _ transport");
+        assert!(split.ends_with("\n\nℹ️ Total: 100 CNY"));
+        assert!(!preview_transaction(&split).contains("ℹ️"));
+        assert!(preview("10USD cash food @ 7CNY lunch").ends_with("\n\nℹ️ Total: 70 CNY"));
+        assert!(!Transaction::today_from_command(
+            &cmd("10 cash food lunch"),
+            &accounts,
+            &self::config(""),
+            &ctx
+        )
+        .unwrap()
+        .preview()
+        .contains("ℹ️"));
+    }
+
+    #[test]
+    fn test_expense_sign() {
+        let accounts = accounts();
+        let parse = |config: &Beancount, s: &str| parse_with(&accounts, config, s).unwrap();
+        let positive = config("");
+        let negative = config(r#"expense_sign = "negative""#);
+        let expected = format!(
+            "{} * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Alipay -10 CNY\n",
+            naive_today().format("%F")
+        );
+        assert_eq!(parse(&positive, "10 ali food lunch"), expected);
+        assert_eq!(parse(&negative, "-10 ali food lunch"), expected);
+        // an amount of the other sign is a refund
+        let refund = format!(
+            "{} * \"refund\"\n    Expenses:Food -10 CNY\n    Assets:Alipay 10 CNY\n",
+            naive_today().format("%F")
+        );
+        assert_eq!(parse(&positive, "-10 ali food refund"), refund);
+        assert_eq!(parse(&negative, "10 ali food refund"), refund);
+    }
+
+    #[test]
+    fn test_split_legs() {
+        let accounts = accounts();
+        let config = config("");
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        let today = naive_today().format("%F");
+        assert_eq!(
+            parse("'100 CNY' card 60 food _ transport taxi home").unwrap(),
+            format!(
+                "{} * \"taxi home\"\n    Expenses:Food 60 CNY\n    Expenses:Transport 40 CNY\n    Liabilities:CreditCard -100 CNY\n",
+                today
+            )
+        );
+        assert_eq!(
+            parse("100 card _ food 30.5 transport").unwrap(),
+            format!(
+                "{} * \"\"\n    Expenses:Food 69.5 CNY\n    Expenses:Transport 30.5 CNY\n    Liabilities:CreditCard -100 CNY\n",
+                today
+            )
+        );
+        assert!(parse("100 card 60 food 40 transport").is_ok());
+        assert_eq!(
+            parse("100 card _ food _ transport")
+                .unwrap_err()
+                .to_string(),
+            "More than one split amount is omitted"
+        );
+        assert_eq!(
+            parse("100 card 60 food 30 transport")
+                .unwrap_err()
+                .to_string(),
+            "Split amounts add up to 90 CNY, not 100 CNY"
+        );
+        assert_eq!(
+            parse("100 card 60USD food _ transport")
+                .unwrap_err()
+                .to_string(),
+            "Split amount 60 USD is not in CNY"
+        );
+        assert_eq!(
+            parse("100 card 60 food _").unwrap_err().to_string(),
+            "Not enough arguments: expense account of _"
+        );
+    }
+
+    #[test]
+    fn test_max_postings() {
+        let accounts = accounts();
+        let config = config("max_postings = 4");
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        assert!(parse("100 cash 50 food 30 transport _ food").is_ok());
+        assert!(parse("100 50 cash _ card | 50 food _ transport").is_ok());
+        assert_eq!(
+            parse("100 cash 50 food 30 transport 10 food _ transport")
+                .unwrap_err()
+                .to_string(),
+            "Too many postings: 5 (maximum 4)"
+        );
+        assert_eq!(
+            parse("100 50 cash _ card | 50 food 30 transport _ food")
+                .unwrap_err()
+                .to_string(),
+            "Too many postings: 5 (maximum 4)"
+        );
+    }
+
+    #[test]
+    fn test_split_sources() {
+        let accounts = accounts();
+        let config = config("");
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        let today = naive_today().format("%F");
+        assert_eq!(
+            parse("'100 CNY' 40 cash 60 card food dinner").unwrap(),
+            format!(
+                "{} * \"dinner\"\n    Expenses:Food 100 CNY\n    Assets:Cash -40 CNY\n    Liabilities:CreditCard -60 CNY\n",
+                today
+            )
+        );
+        assert_eq!(
+            parse("100 40 cash _ card | 70 food _ transport").unwrap(),
+            format!(
+                "{} * \"\"\n    Expenses:Food 70 CNY\n    Expenses:Transport 30 CNY\n    Assets:Cash -40 CNY\n    Liabilities:CreditCard -60 CNY\n",
+                today
+            )
+        );
+        // the separator is optional if only one side is split
+        assert!(parse("100 cash | 70 food _ transport").is_ok());
+        assert_eq!(
+            parse("100 40 cash 50 card food dinner")
+                .unwrap_err()
+                .to_string(),
+            "Split amounts add up to 90 CNY, not 100 CNY"
+        );
+        // without the separator, the expense split is taken as a part of the source split
+        assert_eq!(
+            parse("100 40 cash _ card 70 food _ transport")
+                .unwrap_err()
+                .to_string(),
+            "Not enough arguments: expense account"
+        );
+    }
+
+    #[test]
+    fn test_price() {
+        let accounts = accounts();
+        let config = config("[precision]\nCNY = 2");
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        let today = naive_today().format("%F");
+        assert_eq!(
+            parse("'100 USD' card '710 CNY' food @ 7.1 dinner").unwrap(),
+            format!(
+                "{} * \"dinner\"\n    Expenses:Food 710 CNY\n    Liabilities:CreditCard -100 USD @ 7.1 CNY\n",
+                today
+            )
+        );
+        assert_eq!(
+            parse("100USD card 500 food _ transport @ '7.1 CNY'").unwrap(),
+            format!(
+                "{} * \"\"\n    Expenses:Food 500 CNY\n    Expenses:Transport 210.0 CNY\n    Liabilities:CreditCard -100 USD @ 7.1 CNY\n",
+                today
+            )
+        );
+        // the converted amount is rounded, within the tolerance
+        assert!(parse("10.01USD card food @ '7.123 CNY'")
+            .unwrap()
+            .contains("    Expenses:Food 71.30 CNY\n"));
+        assert_eq!(
+            parse("100USD card 700CNY food @ 7.1")
+                .unwrap_err()
+                .to_string(),
+            "Split amounts add up to 700 CNY, not 710.0 CNY"
+        );
+        assert_eq!(
+            parse("100USD card food @ 7.1").unwrap_err().to_string(),
+            "Currency of price 7.1 is unknown"
+        );
+        assert_eq!(
+            parse("100USD card food @ '7.1 USD'")
+                .unwrap_err()
+                .to_string(),
+            "Price 7.1 USD is in the currency of the amount"
+        );
+        assert_eq!(
+            parse("'100 USD' card '710 CNY' food")
+                .unwrap_err()
+                .to_string(),
+            "Split amount 710 CNY is not in USD"
+        );
+    }
+
+    #[test]
+    fn test_check_balance() {
+        let amount = |n: &str, currency| Amount {
+            number: n.parse().unwrap(),
+            currency,
+        };
+        let priced = |n, price| {
+            let mut posting = Posting::new("Assets:Cash", amount(n, "USD"));
+            posting.price = Some(amount(price, "CNY"));
+            posting
+        };
+        let food = |n| Posting::new("Expenses:Food", amount(n, "CNY"));
+        assert!(check_balance(&[food("710"), priced("-100", "7.1")]).is_ok());
+        assert!(check_balance(&[food("71.30"), priced("-10.01", "7.123")]).is_ok());
+        assert_eq!(
+            check_balance(&[food("71.3"), priced("-10.01", "7.1")])
+                .unwrap_err()
+                .to_string(),
+            "Transaction does not balance: 0.229 CNY"
+        );
+        assert!(check_balance(&[food("10"), food("-9.99")]).is_err());
+    }
+
+    #[test]
+    fn test_signed_amounts() {
+        let mut accounts = accounts();
+        accounts.push("Income:Salary".to_string());
+        let config = config("");
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        let txn = |postings: &str| format!("{} * \"\"\n{}", naive_today().format("%F"), postings);
+        assert_eq!(
+            parse("+100 salary cash").unwrap(),
+            txn("    Assets:Cash 100 CNY\n    Income:Salary -100 CNY\n")
+        );
+        assert_eq!(
+            parse("+100 salary cash").unwrap(),
+            parse("< 100 salary cash").unwrap()
+        );
+        assert_eq!(
+            parse("-10 cash food").unwrap(),
+            txn("    Expenses:Food -10 CNY\n    Assets:Cash 10 CNY\n")
+        );
+        assert_eq!(
+            parse("10 cash food").unwrap(),
+            txn("    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY\n")
+        );
+        assert_eq!(
+            Amount::parse("+10USD"),
+            Some((Decimal::new(10, 0), Some("USD")))
+        );
+        assert_eq!(Amount::parse("-10"), Some((Decimal::new(-10, 0), None)));
+        assert_eq!(Amount::parse("+-10"), None);
+    }
+
+    #[test]
+    fn test_income() {
+        let accounts: Vec<_> = [
+            "Assets:Bank",
+            "Income:Salary",
+            "Income:Bonus",
+            "Expenses:Tax",
+            "Expenses:Insurance",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        let config = config(r#"expense_sign = "negative""#);
+        let parse = |s: &str| parse_with(&accounts, &config, s);
+        let today = naive_today().format("%F");
+        assert_eq!(
+            parse("< 5000 salary _ bank 800 tax 200 insurance paycheck").unwrap(),
+            format!(
+                "{} * \"paycheck\"\n    Assets:Bank 4000 CNY\n    Expenses:Tax 800 CNY\n    Expenses:Insurance 200 CNY\n    Income:Salary -5000 CNY\n",
+                today
+            )
+        );
+        assert_eq!(
+            parse("< 1000 bonus bank").unwrap(),
+            format!(
+                "{} * \"\"\n    Assets:Bank 1000 CNY\n    Income:Bonus -1000 CNY\n",
+                today
+            )
+        );
+        assert_eq!(
+            parse("< 5000 bank 4000 salary _ tax")
+                .unwrap_err()
+                .to_string(),
+            "Invalid income account"
+        );
+        assert_eq!(
+            parse("< 5000 salary 4000 bank _ bonus")
+                .unwrap_err()
+                .to_string(),
+            "Invalid account"
+        );
+        assert_eq!(
+            parse("< 5000 salary 4000 bank 800 tax")
+                .unwrap_err()
+                .to_string(),
+            "Split amounts add up to 4800 CNY, not 5000 CNY"
+        );
+    }
+
+    #[test]
+    fn test_accounts_bom() {
+        let root = crate::utils::temp_dir("accounts_bom");
+        std::fs::write(
+            root.join("accounts.bean"),
+            "\u{feff}; opened on arrival\n2021-01-01 open Assets:Cash\n2021-01-01 open Expenses:Food\n",
+        )
+        .unwrap();
+        assert_eq!(
+            get_accounts(&root).unwrap(),
+            ["Assets:Cash", "Expenses:Food"]
+        );
+        std::fs::write(
+            root.join("accounts.bean"),
+            "\u{feff}2021-01-01 open Assets:Cash\n2021-02-01 close Assets:Cash\n",
+        )
+        .unwrap();
+        assert!(get_accounts(&root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_close_account() {
+        let root = crate::utils::temp_dir("close_account");
+        fs::write(
+            root.join("accounts.bean"),
+            "2021-01-01 open Assets:Cash\n2021-01-01 open Expenses:Food:Snack\n",
+        )
+        .unwrap();
+        assert_eq!(AccountCache::read(&root).unwrap().accounts().len(), 2);
+
+        let date = NaiveDate::from_ymd(2021, 5, 1);
+        close_account(&root, "Expenses:Food:Snack", date).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("accounts.bean")).unwrap(),
+            "2021-01-01 open Assets:Cash\n2021-01-01 open Expenses:Food:Snack\n\n2021-05-01 close Expenses:Food:Snack\n"
+        );
+        assert_eq!(
+            AccountCache::read(&root).unwrap().accounts(),
+            ["Assets:Cash"]
+        );
+
+        assert_eq!(
+            close_account(&root, "Expenses:Food:Snack", date)
+                .unwrap_err()
+                .to_string(),
+            "Account Expenses:Food:Snack is already closed"
+        );
+        assert_eq!(
+            close_account(&root, "Expenses:Food", date)
+                .unwrap_err()
+                .to_string(),
+            "Account Expenses:Food is not opened"
+        );
+    }
+
+    #[test]
+    fn test_tag_spaces() {
+        let accounts = accounts();
+        let parse = |config: &Beancount, s: &str| {
+            parse_with(&accounts, config, s).map(|t| t.lines().next().unwrap()[11..].to_string())
+        };
+        let reject = config("");
+        let hyphen = config(r#"tag_spaces = "hyphen""#);
+        assert_eq!(
+            parse(&reject, "'#trip japan' 10 ali food")
+                .unwrap_err()
+                .to_string(),
+            "Tag '#trip japan' contains whitespace"
+        );
+        assert_eq!(
+            parse(&hyphen, "'#trip  japan ' #food 10 ali food").unwrap(),
+            r#"* "" #trip-japan #food"#
+        );
+        // tags without whitespace are not affected
+        assert_eq!(
+            parse(&reject, "#trip-japan 10 ali food").unwrap(),
+            r#"* "" #trip-japan"#
+        );
+    }
+
+    #[test]
+    fn test_transaction_file() {
+        let date = NaiveDate::from_ymd(2021, 3, 5);
+        assert_eq!(
+            transaction_file("/ledger", date, Granularity::Month),
+            Path::new("/ledger/txs/2021/03.bean")
+        );
+        assert_eq!(
+            transaction_file("/ledger", date, Granularity::Day),
+            Path::new("/ledger/txs/2021/03/05.bean")
+        );
+        assert_eq!(
+            transaction_date("2021-03-05 * \"lunch\"\n    Expenses:Food 10 CNY"),
+            Some(date)
+        );
+        assert_eq!(transaction_date("2021-03"), None);
+    }
+
+    #[test]
+    fn test_append_to_missing_dirs() {
+        let root = crate::utils::temp_dir("append_missing_dirs");
+        // neither txs nor txs/2021 exists
+        let file = transaction_file(&root, NaiveDate::from_ymd(2021, 3, 5), Granularity::Month);
+        append_to_file("2021-03-05 * \"lunch\"", &file, 1).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "2021-03-05 * \"lunch\"\n"
+        );
+    }
+
+    #[test]
+    fn test_append_diff() {
+        let txn = "2021-03-05 * \"dinner\"\n    Expenses:Food 20 CNY\n    Assets:Cash -20 CNY";
+        let existing = "option \"title\" \"ledger\"\n\n2021-03-05 * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY\n";
+        assert_eq!(
+            append_diff(Some(existing), txn, "txs/2021/03.bean", 1),
+            "--- a/txs/2021/03.bean\n+++ b/txs/2021/03.bean\n@@ -3,3 +3,7 @@\n \
+             2021-03-05 * \"lunch\"\n     Expenses:Food 10 CNY\n     Assets:Cash -10 CNY\n+\n\
+             +2021-03-05 * \"dinner\"\n+    Expenses:Food 20 CNY\n+    Assets:Cash -20 CNY\n"
+        );
+        assert_eq!(
+            append_diff(Some("2021-03-01 * \"a\"\n"), "2021-03-05 * \"b\"", "03.bean", 0),
+            "--- a/03.bean\n+++ b/03.bean\n@@ -1,1 +1,2 @@\n 2021-03-01 * \"a\"\n+2021-03-05 * \"b\"\n"
+        );
+        assert_eq!(
+            append_diff(None, txn, "txs/2021/03.bean", 1),
+            "--- /dev/null\n+++ b/txs/2021/03.bean\n@@ -0,0 +1,3 @@\n\
+             +2021-03-05 * \"dinner\"\n+    Expenses:Food 20 CNY\n+    Assets:Cash -20 CNY\n"
+        );
+    }
+
+    #[test]
+    fn test_prepend_to_file() {
+        let root = crate::utils::temp_dir("prepend_to_file");
+        let file = root.join("03.bean");
+        let header =
+            "; household expenses\n; imported from the bank\noption \"title\" \"ledger\"\n";
+        // header only
+        fs::write(&file, header).unwrap();
+        prepend_to_file("2021-03-01 * \"a\"\n    Expenses:Food 1 CNY", &file, 1).unwrap();
+        prepend_to_file("2021-03-02 * \"b\"", &file, 1).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            format!(
+                "{}\n2021-03-02 * \"b\"\n\n2021-03-01 * \"a\"\n    Expenses:Food 1 CNY\n",
+                header
+            )
+        );
+        let existing = fs::read_to_string(&file).unwrap();
+        assert_eq!(
+            prepend_diff(Some(&existing), "2021-03-03 * \"c\"", "03.bean", 1),
+            "--- a/03.bean\n+++ b/03.bean\n@@ -2,6 +2,8 @@\n ; imported from the bank\n option \"title\" \"ledger\"\n \n\
+             +2021-03-03 * \"c\"\n+\n 2021-03-02 * \"b\"\n \n 2021-03-01 * \"a\"\n"
+        );
+
+        // empty and missing files
+        let file = root.join("04.bean");
+        prepend_to_file("2021-04-01 * \"a\"", &file, 1).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "2021-04-01 * \"a\"\n");
+        fs::write(&file, "").unwrap();
+        prepend_to_file("2021-04-02 * \"b\"", &file, 2).unwrap();
+        prepend_to_file("2021-04-03 * \"c\"", &file, 2).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "2021-04-03 * \"c\"\n\n\n2021-04-02 * \"b\"\n"
+        );
+        assert_eq!(
+            prepend_diff(None, "2021-04-01 * \"a\"", "04.bean", 1),
+            append_diff(None, "2021-04-01 * \"a\"", "04.bean", 1)
+        );
+    }
+
+    #[test]
+    fn test_blank_lines() {
+        let root = crate::utils::temp_dir("blank_lines");
+        for (blank_lines, expected) in [
+            (0, "2021-03-05 * \"lunch\"\n2021-03-05 * \"dinner\"\n"),
+            (1, "2021-03-05 * \"lunch\"\n\n2021-03-05 * \"dinner\"\n"),
+            (2, "2021-03-05 * \"lunch\"\n\n\n2021-03-05 * \"dinner\"\n"),
+        ] {
+            let file = root.join(format!("{}.bean", blank_lines));
+            append_to_file("2021-03-05 * \"lunch\"", &file, blank_lines).unwrap();
+            append_to_file("2021-03-05 * \"dinner\"", &file, blank_lines).unwrap();
+            assert_eq!(fs::read_to_string(&file).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_per_day_files() {
+        let root = crate::utils::temp_dir("per_day_files");
+        let day = |d| NaiveDate::from_ymd(2021, 3, d);
+        let write = |d, txn: &str| {
+            append_to_file(txn, transaction_file(&root, day(d), Granularity::Day), 1).unwrap()
+        };
+        // txs/2021/03 does not exist yet
+        write(5, "2021-03-05 * \"lunch\"");
+        write(5, "2021-03-05 * \"dinner\"");
+        write(1, "2021-03-01 * \"breakfast\"");
+        assert_eq!(
+            fs::read_to_string(root.join("txs/2021/03/05.bean")).unwrap(),
+            "2021-03-05 * \"lunch\"\n\n2021-03-05 * \"dinner\"\n"
+        );
+        assert_eq!(
+            String::from_utf8(read_month(&root, day(1), Granularity::Day).unwrap()).unwrap(),
+            "2021-03-01 * \"breakfast\"\n\n2021-03-05 * \"lunch\"\n\n2021-03-05 * \"dinner\"\n"
+        );
+        assert!(
+            read_month(&root, NaiveDate::from_ymd(2021, 4, 1), Granularity::Day)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(read_month(&root, day(1), Granularity::Month)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_account_delta() {
+        let root = crate::utils::temp_dir("account_delta");
+        let write = |date: NaiveDate, txn: &str| {
+            append_to_file(txn, transaction_file(&root, date, Granularity::Month), 1).unwrap()
+        };
+        let feb = |d| NaiveDate::from_ymd(2021, 2, d);
+        let mar = |d| NaiveDate::from_ymd(2021, 3, d);
+        write(
+            feb(20),
+            "2021-02-20 * \"old\"\n  Assets:Cash  -5 CNY\n  Expenses:Food  5 CNY",
+        );
+        write(
+            feb(27),
+            "2021-02-27 * \"lunch\"\n  Assets:Cash  -12.5 CNY\n  Expenses:Food  12.5 CNY",
+        );
+        write(
+            mar(1),
+            "2021-03-01 * \"snack\"\n  Assets:Cash  -3 CNY\n  Expenses:Food:Snack  3 CNY",
+        );
+        write(
+            mar(2),
+            "2021-03-02 * \"dinner\"\n  Assets:Card  -4 USD\n  Expenses:Food  4 USD\n  Expenses:FoodTax  1 USD",
+        );
+        write(
+            mar(9),
+            "2021-03-09 * \"later\"\n  Assets:Cash  -7 CNY\n  Expenses:Food  7 CNY",
+        );
+        let totals = account_delta(
+            &root,
+            Granularity::Month,
+            "Expenses:Food",
+            ":",
+            feb(25),
+            mar(3),
+        )
+        .unwrap();
+        let expected: BTreeMap<_, Decimal> = vec![
+            ("CNY".to_string(), "15.5".parse().unwrap()),
+            ("USD".to_string(), "4".parse().unwrap()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(totals, expected);
+        let totals = account_delta(
+            &root,
+            Granularity::Month,
+            "Assets:Cash",
+            ":",
+            mar(1),
+            mar(31),
+        )
+        .unwrap();
+        assert_eq!(totals["CNY"], "-10".parse().unwrap());
+        assert!(account_delta(
+            &root,
+            Granularity::Month,
+            "Assets:Cash",
+            ":",
+            mar(10),
+            mar(11)
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn test_account_cache_refresh() {
+        let root = crate::utils::temp_dir("account_cache");
+        fs::write(root.join("accounts.bean"), "2021-01-01 open Assets:Cash\n").unwrap();
+        let cache = AccountCache::read(&root).unwrap();
+        assert_eq!(cache.accounts(), ["Assets:Cash"]);
+        assert_eq!(cache.fresh(&root).unwrap(), Some(cache.accounts()));
+        assert_eq!(AccountCache::default().fresh(&root).unwrap(), None);
+
+        // the modification time may not change within a short period, so a re-read is needed
+        fs::write(
+            root.join("accounts.bean"),
+            "2021-01-01 open Assets:Cash\n2021-01-01 open Expenses:Food\n",
+        )
+        .unwrap();
+        let cache = AccountCache::read(&root).unwrap();
+        assert_eq!(cache.accounts(), ["Assets:Cash", "Expenses:Food"]);
     }
 }
@@ -1,38 +1,871 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
 
 use anyhow::{anyhow, ensure, Context, Result};
 
-pub fn check_repo(repo: &str) -> Result<()> {
+/// Remote and branch to pull from and push to. The upstream of the current branch is used if
+/// `remote` is not set, and `branch` is ignored in that case.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Upstream<'a> {
+    pub remote: Option<&'a str>,
+    pub branch: Option<&'a str>,
+}
+
+impl<'a> Upstream<'a> {
+    fn args(&self) -> Vec<&'a str> {
+        match (self.remote, self.branch) {
+            (Some(remote), Some(branch)) => vec![remote, branch],
+            (Some(remote), None) => vec![remote],
+            (None, _) => vec![],
+        }
+    }
+}
+
+/// Makes sure `repo` is a git repository, returning its git directory
+pub fn ensure_repo(repo: &str) -> Result<PathBuf> {
     let out = Command::new("git")
-        .args(&["-C", repo, "pull", "--rebase"])
+        .args(["-C", repo, "rev-parse", "--git-dir"])
         .output()
-        .context("execution of git pull --rebase failed")?;
-    if !out.status.success() {
-        let stderr = String::from_utf8_lossy(&out.stderr).to_string();
-        return Err(anyhow!("git pull --rebase failed").context(stderr));
-    }
+        .context("execution of git rev-parse failed")?;
+    ensure!(out.status.success(), "{} is not a git repository", repo);
+    Ok(Path::new(repo).join(String::from_utf8_lossy(&out.stdout).trim()))
+}
 
+/// Makes sure the repository is neither in the middle of a rebase nor has uncommitted changes
+fn ensure_clean(repo: &str) -> Result<()> {
+    let git_dir = ensure_repo(repo)?;
+    ensure!(
+        !git_dir.join("rebase-merge").exists() && !git_dir.join("rebase-apply").exists(),
+        "repo is mid-rebase; resolve manually"
+    );
+
+    let out = Command::new("git")
+        .args(["-C", repo, "status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .context("execution of git status failed")?;
+    ensure!(out.status.success(), "git status failed");
+    ensure!(
+        out.stdout.is_empty(),
+        "repo has uncommitted changes; commit or discard them manually"
+    );
     Ok(())
 }
 
-pub fn commit_file(repo: &str, file: &Path, orig_cmd: Option<&str>) -> Result<()> {
-    // TODO: capture error message
-    let st = Command::new("git")
-        .args(&["-C", repo, "add"])
-        .arg(file)
-        .status()?;
-    ensure!(st.success(), "git add failed");
+/// Operations on the ledger repository
+pub trait Repo {
+    /// Brings the repository up to date with its upstream
+    fn pull(&self) -> Result<()>;
+    /// Stages `file` for the next commit
+    fn add(&self, file: &Path) -> Result<()>;
+    /// Commits the staged changes, with `orig_cmd` as the body of the commit message
+    fn commit(&self, message: &str, orig_cmd: Option<&str>) -> Result<()>;
+    /// Pushes the commits to the upstream
+    fn push(&self) -> Result<()>;
+    /// Whether pushes after commits are deferred
+    fn defers_push(&self) -> bool {
+        false
+    }
+    /// Makes sure the repository can be committed to
+    fn ensure_clean(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Unstages the changes to `file` after writing or committing it failed
+    fn unstage(&self, _file: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// [`Repo`] backed by the `git` command
+#[derive(Debug, Clone, Copy)]
+pub struct GitRepo<'a> {
+    pub path: &'a str,
+    pub upstream: Upstream<'a>,
+    /// Seconds within which a commit is squashed into the unpushed HEAD commit of the same
+    /// subject. Pushes are deferred to [`GitRepo::push_due`] if set.
+    pub squash_window: Option<i64>,
+    /// Whether a commit is only squashed into a HEAD commit changing the same files
+    pub squash_same_file: bool,
+}
+
+impl<'a> Repo for GitRepo<'a> {
+    fn pull(&self) -> Result<()> {
+        // changes left in the working tree do not block read-only commands
+        let out = Command::new("git")
+            .args(["-C", self.path, "pull", "--rebase", "--autostash"])
+            .args(self.upstream.args())
+            .output()
+            .context("execution of git pull --rebase failed")?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+            return Err(anyhow!("git pull --rebase failed").context(stderr));
+        }
+        Ok(())
+    }
+
+    fn add(&self, file: &Path) -> Result<()> {
+        // TODO: capture error message
+        let st = Command::new("git")
+            .args(["-C", self.path, "add"])
+            .arg(file)
+            .status()?;
+        ensure!(st.success(), "git add failed");
+        Ok(())
+    }
+
+    fn commit(&self, message: &str, orig_cmd: Option<&str>) -> Result<()> {
+        let mut message = message.to_string();
+        if self.squashable(&message)? {
+            // keep the message of the squashed commit, which includes its original commands
+            let previous = self.head_format("%B")?;
+            let st = Command::new("git")
+                .args(["-C", self.path, "reset", "-q", "--soft", "HEAD~1"])
+                .status()?;
+            ensure!(st.success(), "git reset failed");
+            message = previous.trim_end().to_string();
+        }
+        let mut cmd = &mut Command::new("git");
+        cmd = cmd.args(["-C", self.path, "commit", "-m", &message]);
+        if let Some(orig_cmd) = orig_cmd {
+            cmd = cmd.args(["-m", orig_cmd]);
+        }
+        let st = cmd.status()?;
+        ensure!(st.success(), "git commit failed");
+        Ok(())
+    }
+
+    fn push(&self) -> Result<()> {
+        let st = Command::new("git")
+            .args(["-C", self.path, "push"])
+            .args(self.upstream.args())
+            .status()?;
+        ensure!(st.success(), "git push failed");
+        Ok(())
+    }
+
+    fn defers_push(&self) -> bool {
+        self.squash_window.is_some()
+    }
 
-    let mut cmd = &mut Command::new("git");
-    cmd = cmd.args(&["-C", repo, "commit", "-m", "Add a transaction"]);
-    if let Some(orig_cmd) = orig_cmd {
-        cmd = cmd.args(&["-m", orig_cmd]);
+    fn ensure_clean(&self) -> Result<()> {
+        ensure_clean(self.path)
     }
-    let st = cmd.status()?;
-    ensure!(st.success(), "git commit failed");
 
-    let st = Command::new("git").args(&["-C", repo, "push"]).status()?;
-    ensure!(st.success(), "git push failed");
+    fn unstage(&self, file: &Path) -> Result<()> {
+        // fails harmlessly without a HEAD commit, in which case nothing is staged
+        Command::new("git")
+            .args(["-C", self.path, "reset", "-q", "--"])
+            .arg(file)
+            .status()?;
+        Ok(())
+    }
+}
+
+impl<'a> GitRepo<'a> {
+    /// Returns the HEAD commit formatted by `format` of `git log`
+    fn head_format(&self, format: &str) -> Result<String> {
+        let out = Command::new("git")
+            .args(["-C", self.path, "log", "-1"])
+            .arg(format!("--format={}", format))
+            .output()
+            .context("execution of git log failed")?;
+        ensure!(out.status.success(), "git log failed");
+        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+    }
+
+    /// Returns the commit time of HEAD
+    fn head_time(&self) -> Result<i64> {
+        let time = self.head_format("%ct")?;
+        time.trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid commit time {:?}", time))
+    }
+
+    /// Determines whether a commit of `message` should be squashed into the HEAD commit, which
+    /// has to be unpushed, of the same subject, and made within the squash window
+    fn squashable(&self, message: &str) -> Result<bool> {
+        let window = match self.squash_window {
+            Some(window) => window,
+            None => return Ok(false),
+        };
+        // nothing is squashed if the unpushed commits are unknown
+        let unpushed = self.unpushed().unwrap_or(0);
+        if unpushed == 0 || self.head_format("%s")?.trim_end() != message {
+            return Ok(false);
+        }
+        let same_files = !self.squash_same_file || self.staged_files()? == self.head_files()?;
+        let now = chrono::Utc::now().timestamp();
+        Ok(squash_allowed(
+            unpushed,
+            same_files,
+            self.head_time()?,
+            now,
+            window,
+        ))
+    }
+
+    /// Returns the sorted lines of the output of `git` run with `args`
+    fn sorted_lines(&self, args: &[&str]) -> Result<Vec<String>> {
+        let out = Command::new("git")
+            .args(["-C", self.path])
+            .args(args)
+            .output()
+            .with_context(|| format!("execution of git {} failed", args[0]))?;
+        ensure!(out.status.success(), "git {} failed", args[0]);
+        let mut lines: Vec<_> = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(ToString::to_string)
+            .collect();
+        lines.sort();
+        Ok(lines)
+    }
+
+    /// Returns the files staged for the next commit
+    fn staged_files(&self) -> Result<Vec<String>> {
+        self.sorted_lines(&["diff", "--cached", "--name-only"])
+    }
+
+    /// Returns the files changed by the HEAD commit
+    fn head_files(&self) -> Result<Vec<String>> {
+        self.sorted_lines(&["diff-tree", "--no-commit-id", "--name-only", "-r", "HEAD"])
+    }
+
+    /// Determines whether the deferred commits should be pushed, i.e. there are unpushed commits
+    /// and the squash window of the HEAD commit has passed
+    pub fn push_due(&self) -> Result<bool> {
+        let window = self.squash_window.unwrap_or(0);
+        let now = chrono::Utc::now().timestamp();
+        Ok(self.unpushed()? > 0 && !squash_due(self.head_time()?, now, window))
+    }
+
+    /// Returns the HEAD commit, or `None` if there are no commits yet
+    pub fn last_commit(&self) -> Result<Option<CommitInfo>> {
+        let st = Command::new("git")
+            .args(["-C", self.path, "rev-parse", "--verify", "-q", "HEAD"])
+            .stdout(std::process::Stdio::null())
+            .status()
+            .context("execution of git rev-parse failed")?;
+        if !st.success() {
+            return Ok(None);
+        }
+        let out = Command::new("git")
+            .args(["-C", self.path, "log", "-1", COMMIT_FORMAT])
+            .output()
+            .context("execution of git log failed")?;
+        ensure!(out.status.success(), "git log failed");
+        let log = String::from_utf8_lossy(&out.stdout);
+        parse_commit(&log)
+            .map(Some)
+            .ok_or_else(|| anyhow!("invalid git log output {:?}", log))
+    }
+
+    /// Returns the number of local commits not pushed to the upstream yet
+    pub fn unpushed(&self) -> Result<usize> {
+        let upstream = match (self.upstream.remote, self.upstream.branch) {
+            (Some(remote), Some(branch)) => format!("{}/{}", remote, branch),
+            (Some(remote), None) => {
+                let out = Command::new("git")
+                    .args(["-C", self.path, "rev-parse", "--abbrev-ref", "HEAD"])
+                    .output()
+                    .context("execution of git rev-parse failed")?;
+                ensure!(out.status.success(), "git rev-parse failed");
+                format!("{}/{}", remote, String::from_utf8_lossy(&out.stdout).trim())
+            }
+            (None, _) => "@{u}".to_string(),
+        };
+        let out = Command::new("git")
+            .args(["-C", self.path, "rev-list", "--count"])
+            .arg(format!("{}..HEAD", upstream))
+            .output()
+            .context("execution of git rev-list failed")?;
+        ensure!(
+            out.status.success(),
+            "no upstream {} to compare with",
+            upstream
+        );
+        parse_count(&String::from_utf8_lossy(&out.stdout))
+    }
+}
+
+/// Summary of a commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub hash: String,
+    pub author: String,
+    pub subject: String,
+}
+
+/// `git log` format of [`CommitInfo`], parsed by [`parse_commit`]
+const COMMIT_FORMAT: &str = "--format=%h%x00%an%x00%s";
+
+/// Parses the output of `git log` in [`COMMIT_FORMAT`]
+fn parse_commit(s: &str) -> Option<CommitInfo> {
+    let mut fields = s.trim_end_matches('\n').splitn(3, '\0');
+    Some(CommitInfo {
+        hash: fields.next().filter(|h| !h.is_empty())?.to_string(),
+        author: fields.next()?.to_string(),
+        subject: fields.next()?.to_string(),
+    })
+}
+
+/// Determines whether a commit made at `now` is squashed into the HEAD commit of the same subject
+/// made at `head_time`, which requires some commits to be unpushed so that HEAD is one of them,
+/// and the files changed to be the same if required
+fn squash_allowed(
+    unpushed: usize,
+    same_files: bool,
+    head_time: i64,
+    now: i64,
+    window: i64,
+) -> bool {
+    unpushed > 0 && same_files && squash_due(head_time, now, window)
+}
+
+/// Determines whether a commit made at `now` is within the squash window of the HEAD commit made
+/// at `head_time`
+fn squash_due(head_time: i64, now: i64, window: i64) -> bool {
+    now - head_time < window
+}
+
+/// Parses the output of `git rev-list --count`
+fn parse_count(s: &str) -> Result<usize> {
+    s.trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid commit count {:?}", s))
+}
+
+/// Unix time of the last successful pull, 0 if the repository has not been pulled
+static LAST_PULL: AtomicI64 = AtomicI64::new(0);
+
+/// Determines whether a read-only query should pull, given the time of the last pull. Without an
+/// `interval` every query pulls.
+fn pull_due(last_pull: i64, now: i64, interval: Option<i64>) -> bool {
+    match interval {
+        Some(interval) => last_pull == 0 || now - last_pull >= interval,
+        None => true,
+    }
+}
+
+/// Same as [`check_repo`] but for commands that only read the repository, which skip the pull if
+/// the last one happened within `interval` seconds
+pub fn check_repo_read(repo: &impl Repo, interval: Option<i64>) -> Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    if pull_due(LAST_PULL.load(Ordering::Relaxed), now, interval) {
+        pull_repo(repo)
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes sure `repo` can be committed to and pulls it, for commands that write the repository
+pub fn check_repo(repo: &impl Repo) -> Result<()> {
+    repo.ensure_clean()?;
+    pull_repo(repo)
+}
+
+/// Pulls `repo`, recording the failure for `/retry` and the time of the success
+fn pull_repo(repo: &impl Repo) -> Result<()> {
+    let r = repo.pull();
+    LAST_FAILURE.record(FailedOp::Pull, &r);
+    r?;
+    LAST_PULL.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
     Ok(())
 }
+
+/// Pushes `repo`, recording the failure for `/retry`
+pub fn push_repo(repo: &impl Repo) -> Result<()> {
+    let r = repo.push();
+    LAST_FAILURE.record(FailedOp::Push, &r);
+    r
+}
+
+/// Forgets the last pull and the last failure, which belong to the previous ledger after
+/// `/setroot`
+pub fn forget_repo() {
+    LAST_PULL.store(0, Ordering::Relaxed);
+    LAST_FAILURE.clear();
+}
+
+/// Git operation that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailedOp {
+    Pull,
+    Push,
+}
+
+/// The last failed git operation, kept until it succeeds
+#[derive(Debug, Default)]
+pub struct LastFailure(Mutex<Option<FailedOp>>);
+
+/// The last failed git operation of the ledger repository, retried by `/retry`
+pub static LAST_FAILURE: LastFailure = LastFailure(Mutex::new(None));
+
+impl LastFailure {
+    /// Records the result `r` of `op`. A successful push also settles a failed pull, whose retry
+    /// would push afterwards anyway.
+    pub fn record(&self, op: FailedOp, r: &Result<()>) {
+        let mut failed = self.0.lock().unwrap();
+        match (r, *failed) {
+            (Err(_), _) => *failed = Some(op),
+            (Ok(()), Some(last)) if last == op || op == FailedOp::Push => *failed = None,
+            (Ok(()), _) => {}
+        }
+    }
+
+    pub fn get(&self) -> Option<FailedOp> {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    /// Retries the last failed operation, pulling before pushing if the pull failed. Returns the
+    /// retried operation, or `None` if nothing failed.
+    pub fn retry(&self, repo: &impl Repo) -> Result<Option<FailedOp>> {
+        let op = match self.get() {
+            Some(op) => op,
+            None => return Ok(None),
+        };
+        if op == FailedOp::Pull {
+            let r = repo.pull();
+            self.record(FailedOp::Pull, &r);
+            r?;
+        }
+        let r = repo.push();
+        self.record(FailedOp::Push, &r);
+        r?;
+        Ok(Some(op))
+    }
+}
+
+/// Outcome of [`commit_file`] once the commit is made
+#[derive(Debug)]
+pub enum Committed {
+    Pushed,
+    /// The push is left to later, see [`Repo::defers_push`]
+    Deferred,
+    /// The commit is made locally but pushing it failed
+    PushFailed(anyhow::Error),
+}
+
+/// Writes `file` by `write`, commits it and pushes the commit. Only the failures before the commit
+/// is made are errors, after which the file is put back as it was before the write, or removed if
+/// the write created it, so that the repository stays clean.
+pub fn commit_file(
+    repo: &impl Repo,
+    file: &Path,
+    write: impl FnOnce() -> Result<()>,
+    message: &str,
+    orig_cmd: Option<&str>,
+) -> Result<Committed> {
+    let original = match std::fs::read(file) {
+        Ok(content) => Some(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("Read file failed"),
+    };
+    let r = write().and_then(|()| {
+        repo.add(file)?;
+        repo.commit(message, orig_cmd).context("Commit file failed")
+    });
+    if let Err(e) = r {
+        let restored = repo.unstage(file).and_then(|()| {
+            match original {
+                Some(content) => std::fs::write(file, content),
+                None if file.exists() => std::fs::remove_file(file),
+                None => Ok(()),
+            }
+            .map_err(Into::into)
+        });
+        if let Err(restore) = restored {
+            log::warn!("Restoring {} failed: {:?}", file.display(), restore);
+        }
+        return Err(e);
+    }
+    if repo.defers_push() {
+        return Ok(Committed::Deferred);
+    }
+    Ok(match push_repo(repo) {
+        Ok(()) => Committed::Pushed,
+        Err(e) => Committed::PushFailed(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// [`Repo`] whose pulls and pushes fail while the flags are set
+    #[derive(Default)]
+    struct FlakyRepo {
+        fail_pull: std::cell::Cell<bool>,
+        fail_push: std::cell::Cell<bool>,
+        ops: std::cell::RefCell<Vec<&'static str>>,
+    }
+
+    impl Repo for FlakyRepo {
+        fn pull(&self) -> Result<()> {
+            self.ops.borrow_mut().push("pull");
+            ensure!(!self.fail_pull.get(), "pull failed");
+            Ok(())
+        }
+        fn add(&self, _file: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn commit(&self, _message: &str, _orig_cmd: Option<&str>) -> Result<()> {
+            Ok(())
+        }
+        fn push(&self) -> Result<()> {
+            self.ops.borrow_mut().push("push");
+            ensure!(!self.fail_push.get(), "push failed");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_last_failure() {
+        let failure = LastFailure::default();
+        let repo = FlakyRepo::default();
+        assert_eq!(failure.retry(&repo).unwrap(), None);
+        assert!(repo.ops.borrow().is_empty());
+
+        failure.record(FailedOp::Push, &Err(anyhow!("push failed")));
+        // a successful pull does not settle a failed push
+        failure.record(FailedOp::Pull, &Ok(()));
+        assert_eq!(failure.get(), Some(FailedOp::Push));
+        repo.fail_push.set(true);
+        assert!(failure.retry(&repo).is_err());
+        assert_eq!(failure.get(), Some(FailedOp::Push));
+        repo.fail_push.set(false);
+        assert_eq!(failure.retry(&repo).unwrap(), Some(FailedOp::Push));
+        assert_eq!(failure.get(), None);
+        assert_eq!(*repo.ops.borrow(), ["push", "push"]);
+
+        repo.ops.borrow_mut().clear();
+        failure.record(FailedOp::Pull, &Err(anyhow!("pull failed")));
+        repo.fail_pull.set(true);
+        assert!(failure.retry(&repo).is_err());
+        assert_eq!(failure.get(), Some(FailedOp::Pull));
+        repo.fail_pull.set(false);
+        assert_eq!(failure.retry(&repo).unwrap(), Some(FailedOp::Pull));
+        assert_eq!(failure.get(), None);
+        assert_eq!(*repo.ops.borrow(), ["pull", "pull", "push"]);
+
+        failure.record(FailedOp::Pull, &Err(anyhow!("pull failed")));
+        failure.record(FailedOp::Push, &Ok(()));
+        assert_eq!(failure.get(), None);
+        failure.record(FailedOp::Push, &Err(anyhow!("push failed")));
+        failure.clear();
+        assert_eq!(failure.get(), None);
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {:?} failed", args);
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    /// Creates a bare repository `remote.git` and a working repository `work` with one commit,
+    /// which has `remote.git` as the remote `backup` and no upstream
+    fn setup_repos(name: &str) -> (PathBuf, PathBuf) {
+        let dir = crate::utils::temp_dir(name);
+        let remote = dir.join("remote.git");
+        let work = dir.join("work");
+        git(
+            &dir,
+            &["init", "-q", "--bare", "-b", "ledger", "remote.git"],
+        );
+        git(&dir, &["init", "-q", "-b", "ledger", "work"]);
+        git(&work, &["config", "user.name", "Test"]);
+        git(&work, &["config", "user.email", "test@example.com"]);
+        fs::write(work.join("accounts.bean"), "2021-01-01 open Assets:Cash\n").unwrap();
+        git(&work, &["add", "accounts.bean"]);
+        git(&work, &["commit", "-q", "-m", "Initial commit"]);
+        git(
+            &work,
+            &["remote", "add", "backup", remote.to_str().unwrap()],
+        );
+        git(&work, &["push", "-q", "backup", "ledger"]);
+        (remote, work)
+    }
+
+    #[test]
+    fn test_named_remote() {
+        let (remote, work) = setup_repos("named_remote");
+        let path = work.to_str().unwrap();
+        let repo = GitRepo {
+            path,
+            upstream: Upstream {
+                remote: Some("backup"),
+                branch: Some("ledger"),
+            },
+            squash_window: None,
+            squash_same_file: false,
+        };
+        // there is no upstream configured, so the implicit form fails
+        let implicit = GitRepo {
+            path,
+            upstream: Upstream::default(),
+            squash_window: None,
+            squash_same_file: false,
+        };
+        assert!(check_repo(&implicit).is_err());
+        check_repo(&repo).unwrap();
+
+        let file = work.join("txs.bean");
+        commit_file(
+            &repo,
+            &file,
+            || Ok(fs::write(&file, "2021-01-02 * \"lunch\"\n")?),
+            "Add a transaction",
+            Some("10 cash food lunch"),
+        )
+        .unwrap();
+        let log = git(&remote, &["log", "-1", "--format=%s", "ledger"]);
+        assert_eq!(log.trim(), "Add a transaction");
+        assert_eq!(repo.unpushed().unwrap(), 0);
+        assert!(implicit.unpushed().is_err());
+
+        fs::write(&file, "2021-01-03 * \"dinner\"\n").unwrap();
+        repo.add(&file).unwrap();
+        repo.commit("Add a transaction", None).unwrap();
+        assert_eq!(repo.unpushed().unwrap(), 1);
+        repo.push().unwrap();
+        assert_eq!(repo.unpushed().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unclean_repo() {
+        let (_, work) = setup_repos("unclean_repo");
+        let repo = GitRepo {
+            path: work.to_str().unwrap(),
+            upstream: Upstream {
+                remote: Some("backup"),
+                branch: Some("ledger"),
+            },
+            squash_window: None,
+            squash_same_file: false,
+        };
+        check_repo(&repo).unwrap();
+
+        fs::create_dir(work.join(".git/rebase-merge")).unwrap();
+        let err = check_repo(&repo).unwrap_err();
+        assert_eq!(err.to_string(), "repo is mid-rebase; resolve manually");
+        fs::remove_dir(work.join(".git/rebase-merge")).unwrap();
+        fs::create_dir(work.join(".git/rebase-apply")).unwrap();
+        assert!(check_repo(&repo).is_err());
+        fs::remove_dir(work.join(".git/rebase-apply")).unwrap();
+
+        // untracked files are fine
+        fs::write(work.join("untracked.bean"), "").unwrap();
+        check_repo(&repo).unwrap();
+        fs::write(work.join("accounts.bean"), "").unwrap();
+        let err = check_repo(&repo).unwrap_err();
+        assert!(err.to_string().starts_with("repo has uncommitted changes"));
+        // only the commands writing the repository are blocked
+        check_repo_read(&repo, None).unwrap();
+    }
+
+    #[test]
+    fn test_restore_on_failure() {
+        let (_, work) = setup_repos("restore_on_failure");
+        let repo = GitRepo {
+            path: work.to_str().unwrap(),
+            upstream: Upstream {
+                remote: Some("backup"),
+                branch: Some("ledger"),
+            },
+            squash_window: None,
+            squash_same_file: false,
+        };
+        let accounts = work.join("accounts.bean");
+        let write = |file: &Path| {
+            let file = file.to_path_buf();
+            move || {
+                fs::write(file, "2021-01-02 * \"lunch\"\n")?;
+                Err(anyhow!("write failed"))
+            }
+        };
+        let err = commit_file(&repo, &accounts, write(&accounts), "Add", None).unwrap_err();
+        assert_eq!(err.to_string(), "write failed");
+        assert_eq!(
+            fs::read_to_string(&accounts).unwrap(),
+            "2021-01-01 open Assets:Cash\n"
+        );
+        check_repo(&repo).unwrap();
+
+        // new files are removed, even if the failure is after staging them
+        let new = work.join("txs.bean");
+        let staged = || {
+            fs::write(&new, "2021-01-02 * \"lunch\"\n")?;
+            repo.add(&new)?;
+            Err(anyhow!("write failed"))
+        };
+        assert!(commit_file(&repo, &new, staged, "Add", None).is_err());
+        assert!(!new.exists());
+        check_repo(&repo).unwrap();
+
+        // untracked files existing before the write are kept
+        let untracked = work.join("untracked.bean");
+        fs::write(&untracked, "2021-01-01 * \"breakfast\"\n").unwrap();
+        let err = commit_file(&repo, &untracked, write(&untracked), "Add", None).unwrap_err();
+        assert_eq!(err.to_string(), "write failed");
+        assert_eq!(
+            fs::read_to_string(&untracked).unwrap(),
+            "2021-01-01 * \"breakfast\"\n"
+        );
+        let staged = || {
+            fs::write(&untracked, "2021-01-02 * \"lunch\"\n")?;
+            repo.add(&untracked)?;
+            Err(anyhow!("write failed"))
+        };
+        assert!(commit_file(&repo, &untracked, staged, "Add", None).is_err());
+        assert_eq!(
+            fs::read_to_string(&untracked).unwrap(),
+            "2021-01-01 * \"breakfast\"\n"
+        );
+        check_repo(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_pull_due() {
+        assert!(pull_due(0, 1000, None));
+        assert!(pull_due(990, 1000, None));
+        assert!(pull_due(0, 1000, Some(60)));
+        assert!(!pull_due(990, 1000, Some(60)));
+        assert!(pull_due(940, 1000, Some(60)));
+        assert!(pull_due(900, 1000, Some(60)));
+        assert!(pull_due(990, 1000, Some(0)));
+    }
+
+    #[test]
+    fn test_parse_count() {
+        assert_eq!(parse_count("3\n").unwrap(), 3);
+        assert_eq!(parse_count("0").unwrap(), 0);
+        assert!(parse_count("").is_err());
+        assert!(parse_count("fatal: no upstream").is_err());
+    }
+
+    #[test]
+    fn test_last_commit() {
+        let (_, work) = setup_repos("last_commit");
+        let repo = GitRepo {
+            path: work.to_str().unwrap(),
+            upstream: Upstream::default(),
+            squash_window: None,
+            squash_same_file: false,
+        };
+        let commit = repo.last_commit().unwrap().unwrap();
+        assert_eq!(commit.author, "Test");
+        assert_eq!(commit.subject, "Initial commit");
+        let hash = git(&work, &["rev-parse", "--short", "HEAD"]);
+        assert_eq!(commit.hash, hash.trim());
+
+        let empty = crate::utils::temp_dir("last_commit_empty");
+        git(&empty, &["init", "-q"]);
+        let repo = GitRepo {
+            path: empty.to_str().unwrap(),
+            upstream: Upstream::default(),
+            squash_window: None,
+            squash_same_file: false,
+        };
+        assert_eq!(repo.last_commit().unwrap(), None);
+
+        assert_eq!(parse_commit(""), None);
+        assert_eq!(
+            parse_commit("abc1234\0A B\0Add a transaction: 10\0x\n"),
+            Some(CommitInfo {
+                hash: "abc1234".into(),
+                author: "A B".into(),
+                subject: "Add a transaction: 10\0x".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_squash() {
+        assert!(squash_due(1000, 1000, 60));
+        assert!(squash_due(1000, 1059, 60));
+        assert!(!squash_due(1000, 1060, 60));
+        assert!(!squash_due(1000, 1000, 0));
+
+        let (remote, work) = setup_repos("squash");
+        let repo = GitRepo {
+            path: work.to_str().unwrap(),
+            upstream: Upstream {
+                remote: Some("backup"),
+                branch: Some("ledger"),
+            },
+            squash_window: Some(3600),
+            squash_same_file: false,
+        };
+        let file = work.join("txs.bean");
+        let add = |txn: &str, cmd: &str| {
+            fs::write(&file, txn).unwrap();
+            commit_file(&repo, &file, || Ok(()), "Add a transaction", Some(cmd)).unwrap();
+        };
+        add("lunch", "10 cash food lunch");
+        add("dinner", "20 cash food dinner");
+        // the pushes are deferred, and the second commit is squashed into the first one
+        assert_eq!(repo.unpushed().unwrap(), 1);
+        assert!(!repo.push_due().unwrap());
+        let message = git(&work, &["log", "-1", "--format=%B"]);
+        assert_eq!(
+            message.trim_end(),
+            "Add a transaction\n\n10 cash food lunch\n\n20 cash food dinner"
+        );
+        // other commits are not squashed
+        fs::write(work.join("accounts.bean"), "").unwrap();
+        commit_file(
+            &repo,
+            &work.join("accounts.bean"),
+            || Ok(()),
+            "Close Assets:Cash",
+            None,
+        )
+        .unwrap();
+        assert_eq!(repo.unpushed().unwrap(), 2);
+
+        repo.push().unwrap();
+        let log = git(&remote, &["log", "-1", "--format=%s", "ledger"]);
+        assert_eq!(log.trim(), "Close Assets:Cash");
+        // pushed commits are not squashed
+        add("supper", "30 cash food supper");
+        add("snack", "5 cash food snack");
+        assert_eq!(repo.unpushed().unwrap(), 1);
+
+        // commits to other files are squashed unless the files have to be the same
+        let other = work.join("other.bean");
+        fs::write(&other, "brunch").unwrap();
+        commit_file(&repo, &other, || Ok(()), "Add a transaction", None).unwrap();
+        assert_eq!(repo.unpushed().unwrap(), 1);
+        let repo = GitRepo {
+            squash_same_file: true,
+            ..repo
+        };
+        fs::write(&file, "tea").unwrap();
+        commit_file(&repo, &file, || Ok(()), "Add a transaction", None).unwrap();
+        assert_eq!(repo.unpushed().unwrap(), 2);
+        fs::write(&file, "coffee").unwrap();
+        commit_file(&repo, &file, || Ok(()), "Add a transaction", None).unwrap();
+        assert_eq!(repo.unpushed().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_squash_allowed() {
+        assert!(squash_allowed(1, true, 1000, 1059, 60));
+        // HEAD is pushed
+        assert!(!squash_allowed(0, true, 1000, 1059, 60));
+        // HEAD changes other files
+        assert!(!squash_allowed(1, false, 1000, 1059, 60));
+        // the window has passed
+        assert!(!squash_allowed(3, true, 1000, 1060, 60));
+    }
+}
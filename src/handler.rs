@@ -1,27 +1,86 @@
-use std::fs::File;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
-use log::info;
+use anyhow::{anyhow, ensure, Context, Result};
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use rust_decimal::Decimal;
 
 use tbot::contexts::methods::ChatMethods;
 use tbot::contexts::{Command, DataCallback, Text};
 use tbot::types::callback::Origin;
-use tbot::types::keyboard::inline::{Button, ButtonKind};
-use tbot::types::message::Kind;
+use tbot::types::chat;
+use tbot::types::input_file::Document;
+use tbot::types::keyboard::inline::{Button, ButtonKind, Keyboard};
+use tbot::types::message::{self, Kind};
+use tbot::types::User;
 use tokio::sync::RwLock;
 
-use crate::beancount::{append_to_file, get_accounts, Transaction};
-use crate::git::{check_repo, commit_file};
-use crate::utils::command_split;
-use crate::{get_config, Database};
+use crate::beancount::{
+    account_delta, accounts_file, add_transaction_metadata, add_transaction_tag, amount_above,
+    append_diff, append_to_file, bean_check, close_account, expand_shortcut, find_accounts,
+    get_accounts, is_currency, localize_preview, missing_accounts, prepend_diff, prepend_to_file,
+    preview_transaction, read_month, resolve_account, set_transaction_flag, transaction_date,
+    transaction_file, transaction_flag, AccountCache, ParseContext, Transaction, FLAG_COMPLETE,
+    FLAG_INCOMPLETE,
+};
+use crate::git::{
+    check_repo, check_repo_read, commit_file, ensure_repo, forget_repo, push_repo, Committed,
+    FailedOp, Repo, LAST_FAILURE,
+};
+use crate::utils::{
+    command_split, command_split_lines, elapsed, evaluate, has_root, ledger_today, parse_month,
+};
+use crate::{
+    get_config, set_config, Beancount, Config, Database, EmptyMessage, Preview, ReviewEntry,
+};
+
+/// Actions of the buttons attached to transaction previews and other replies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Commit,
+    Cancel,
+    Approve,
+    Discard,
+    Push,
+    /// Commits a transaction with a large amount after the second confirmation
+    CommitLarge,
+}
+
+const ALL_ACTIONS: [Action; 6] = [
+    Action::Commit,
+    Action::Cancel,
+    Action::Approve,
+    Action::Discard,
+    Action::Push,
+    Action::CommitLarge,
+];
+
+impl Action {
+    /// Returns the callback data sent when the button is pressed
+    fn data(self) -> &'static str {
+        match self {
+            Action::Commit => "commit",
+            Action::Cancel => "cancel",
+            Action::Approve => "approve",
+            Action::Discard => "discard",
+            Action::Push => "push",
+            Action::CommitLarge => "commit_large",
+        }
+    }
+
+    fn from_data(data: &str) -> Option<Self> {
+        ALL_ACTIONS.iter().copied().find(|a| a.data() == data)
+    }
+}
 
 /// Handler for command `/auth`
 pub async fn auth(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
-    let state_file = &get_config().bot.state_file;
     if let Some(ref user) = context.from {
-        if !state.read().await.auth_users.contains(&user.id.0)
+        let chat = context.chat.id.0;
+        if !state.read().await.is_authorized(user.id.0, chat)
             && context.text.value == get_config().bot.secret
         {
             let mut guard = state.write().await;
@@ -29,8 +88,9 @@ pub async fn auth(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) ->
                 let username = user.username.as_deref().unwrap_or("<noname>");
                 info!("Authorizing user {} (@{})", user.id.0, username);
             }
-            guard.auth_users.push(user.id.0);
-            serde_json::to_writer(File::create(state_file)?, &*guard)?;
+            let key = get_config().bot.auth_scope.key(chat);
+            guard.auth_users.insert(user.id.0, key);
+            guard.save()?;
             context.send_message("Authorized!").call().await?;
             context.delete_this_message().call().await?;
         }
@@ -38,12 +98,65 @@ pub async fn auth(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) ->
     Ok(())
 }
 
-/// Handler for command `/accounts`
-pub async fn accounts(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
-    check_repo(&get_config().beancount.root).context("Check repo failed")?;
-    let mut accounts = get_accounts(&get_config().beancount.root).context("get accounts failed")?;
+/// Handler for command `/whoami`
+pub async fn whoami(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    if let Some(ref user) = context.from {
+        let (authorized, admin) = {
+            let guard = state.read().await;
+            (
+                guard.is_authorized(user.id.0, context.chat.id.0),
+                guard.is_admin(user.id.0, context.chat.id.0),
+            )
+        };
+        let msg = whoami_text(user, authorized, admin);
+        context.send_message_in_reply(&msg).call().await?;
+    }
+    Ok(())
+}
+
+/// Returns the reply to `/whoami` describing `user`
+fn whoami_text(user: &User, authorized: bool, admin: bool) -> String {
+    format!(
+        "User id: {}\nUsername: {}\nAuthorized: {}\nAdmin: {}",
+        user.id.0,
+        user.username
+            .as_deref()
+            .map_or("<none>".to_string(), |u| format!("@{}", u)),
+        if authorized { "yes" } else { "no" },
+        if admin { "yes" } else { "no" },
+    )
+}
+
+/// Returns the accounts in `root`, reading them on the blocking thread pool unless the cached
+/// ones are up to date
+async fn load_accounts(state: &RwLock<Database>, root: &str) -> Result<Vec<String>> {
+    if let Some(accounts) = state.read().await.accounts.fresh(root)? {
+        return Ok(accounts.to_vec());
+    }
+    refresh_accounts(state, root).await
+}
+
+/// Re-reads the accounts in `root` on the blocking thread pool regardless of the cache
+async fn refresh_accounts(state: &RwLock<Database>, root: &str) -> Result<Vec<String>> {
+    let path = root.to_string();
+    let cache = tokio::task::spawn_blocking(move || AccountCache::read(path)).await??;
+    let accounts = cache.accounts().to_vec();
+    state.write().await.accounts = cache;
+    Ok(accounts)
+}
+
+/// Handler for command `/accounts`, which sends the accounts as a document if given `--file` or
+/// too long for a message
+pub async fn accounts(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let root = &config.root;
+    check_repo_read(&config.repo(), config.pull_interval).context("Check repo failed")?;
+    let mut accounts = load_accounts(&state, root)
+        .await
+        .context("get accounts failed")?;
     let query = context.text.value.to_lowercase();
-    let query: Vec<_> = query.split_ascii_whitespace().collect();
+    let (file, query): (Vec<_>, Vec<_>) =
+        query.split_ascii_whitespace().partition(|q| *q == "--file");
     let accs: Vec<_> = if query.is_empty() {
         accounts
     } else {
@@ -52,59 +165,1255 @@ pub async fn accounts(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>
             .filter(|ac| query.iter().all(|q| ac.to_lowercase().contains(q)))
             .collect()
     };
-    context.send_message(&accs.join(" ")).call().await?;
+    if accs.is_empty() || !accounts_as_file(&accs, !file.is_empty()) {
+        context
+            .send_message(&accounts_text(
+                &accs,
+                &query,
+                get_config().bot.accounts_limit,
+            ))
+            .call()
+            .await?;
+    } else {
+        let content = accs.join("\n") + "\n";
+        context
+            .send_document(Document::with_bytes("accounts.txt", content.as_bytes()))
+            .call()
+            .await?;
+    }
     Ok(())
 }
 
-/// Handler for messages
-pub async fn command(context: Arc<Text>, _state: Arc<RwLock<Database>>) -> Result<()> {
-    let accounts = get_accounts(&get_config().beancount.root).context("get accounts failed")?;
-    let cmd_split = command_split(&context.text.value)
-        .with_context(|| anyhow!("Invalid command '{}'", context.text.value))?;
-    let txn = Transaction::today_from_command(
-        &cmd_split,
-        &accounts,
-        &get_config().beancount.default_currency,
-    )?;
+/// Returns the message listing `accounts` matching `query`, which tells there are none instead of
+/// being empty as Telegram rejects empty messages. Only the first `limit` accounts are listed.
+fn accounts_text(accounts: &[String], query: &[&str], limit: Option<usize>) -> String {
+    if let Some(limit) = limit.filter(|limit| accounts.len() > *limit) {
+        format!(
+            "{}\n...and {} more, refine your search",
+            accounts[..limit].join(" "),
+            accounts.len() - limit
+        )
+    } else if !accounts.is_empty() {
+        accounts.join(" ")
+    } else if query.is_empty() {
+        "No accounts opened".to_string()
+    } else {
+        format!("No accounts matching {}", query.join(" "))
+    }
+}
+
+/// Length above which the accounts are sent as a document, below the 4096-character limit of
+/// messages
+const ACCOUNTS_INLINE_LIMIT: usize = 4000;
+
+/// Determines whether the accounts are sent as a document, one account per line, instead of a
+/// message
+fn accounts_as_file(accounts: &[String], force: bool) -> bool {
+    force
+        || accounts
+            .iter()
+            .map(|ac| ac.chars().count() + 1)
+            .sum::<usize>()
+            > ACCOUNTS_INLINE_LIMIT
+}
+
+/// Prefix of the callback data of the buttons replied by `/find`, followed by the account
+const FIND_PREFIX: &str = "find:";
+/// Maximum number of accounts replied by `/find`
+const FIND_LIMIT: usize = 10;
+
+/// Returns the accounts matching `term` along with the callback data of their buttons, skipping
+/// the accounts too long for the 64-byte limit of callback data
+fn find_candidates<'a>(accounts: &'a [String], term: &str) -> (usize, Vec<(&'a str, String)>) {
+    let matched = find_accounts(accounts, term);
+    let candidates = matched
+        .iter()
+        .map(|ac| (ac.as_str(), format!("{}{}", FIND_PREFIX, ac)))
+        .filter(|(_, data)| data.len() <= 64)
+        .take(FIND_LIMIT)
+        .collect();
+    (matched.len(), candidates)
+}
+
+/// Handler for command `/find`, which replies the matching accounts as buttons sending their full
+/// names when tapped
+pub async fn find(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let term = context.text.value.trim();
+    ensure!(!term.is_empty(), "Usage: /find <term>");
+    check_repo_read(&config.repo(), config.pull_interval).context("Check repo failed")?;
+    let accounts = load_accounts(&state, &config.root)
+        .await
+        .context("get accounts failed")?;
+    let (count, candidates) = find_candidates(&accounts, term);
+    if count == 0 {
+        context.send_message("No matched account").call().await?;
+        return Ok(());
+    }
+    let buttons: Vec<_> = candidates
+        .iter()
+        .map(|(account, data)| Button::new(account, ButtonKind::CallbackData(data)))
+        .collect();
+    let keyboard: Vec<_> = buttons.chunks(1).collect();
+    let msg = if count > candidates.len() {
+        format!("{} matched accounts, showing {}", count, candidates.len())
+    } else {
+        format!("{} matched account(s)", count)
+    };
+    context
+        .send_message(&msg)
+        .reply_markup(keyboard.as_slice())
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Handler for command `/refresh`
+pub async fn refresh(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let root = &config.root;
+    check_repo(&config.repo()).context("Check repo failed")?;
+    let count = refresh_accounts(&state, root)
+        .await
+        .context("get accounts failed")?
+        .len();
+    context
+        .send_message(&format!("Refreshed, {} accounts", count))
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Handler for command `/review`
+pub async fn review(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let queue = state.read().await.review_queue.clone();
+    if queue.is_empty() {
+        context
+            .send_message("No transactions to review")
+            .call()
+            .await?;
+        return Ok(());
+    }
     let keyboard = vec![
-        Button::new("提交", ButtonKind::CallbackData("commit")),
-        Button::new("取消", ButtonKind::CallbackData("cancel")),
+        Button::new("批准", ButtonKind::CallbackData(Action::Approve.data())),
+        Button::new("丢弃", ButtonKind::CallbackData(Action::Discard.data())),
     ];
+    for entry in queue.iter() {
+        context
+            .send_message(&entry.txn)
+            .reply_markup(&[keyboard.as_slice()][..])
+            .call()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Handler for command `/pending`
+pub async fn pending(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let count = get_config().beancount.repo().unpushed()?;
+    if count == 0 {
+        context
+            .send_message("No commits waiting to be pushed")
+            .call()
+            .await?;
+    } else {
+        let keyboard = [Button::new(
+            "推送",
+            ButtonKind::CallbackData(Action::Push.data()),
+        )];
+        context
+            .send_message(&format!("{} commit(s) waiting to be pushed", count))
+            .reply_markup(&[&keyboard[..]][..])
+            .call()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Handler for command `/push`
+pub async fn push(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    push_repo(&get_config().beancount.repo())?;
+    context.send_message("Pushed").call().await?;
+    Ok(())
+}
+
+/// Handler for command `/retry`, which retries the last failed git operation
+pub async fn retry(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let msg = match LAST_FAILURE.retry(&get_config().beancount.repo())? {
+        Some(FailedOp::Pull) => "Pulled and pushed",
+        Some(FailedOp::Push) => "Pushed",
+        None => "No failed git operation",
+    };
+    context.send_message(msg).call().await?;
+    Ok(())
+}
+
+/// Handler for command `/lastcommit`
+pub async fn lastcommit(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let msg = match get_config().beancount.repo().last_commit()? {
+        Some(commit) => format!(
+            "{} {}\nAuthor: {}",
+            commit.hash, commit.subject, commit.author
+        ),
+        None => "No commits yet".to_string(),
+    };
+    context.send_message(&msg).call().await?;
+    Ok(())
+}
+
+/// Handler for command `/calc`
+pub async fn calc(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let value = evaluate(&context.text.value)?;
+    context
+        .send_message_in_reply(&format!("= {}", value))
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Returns the numbered arguments `text` is split into
+fn tokens_text(text: &str) -> Result<String> {
+    let tokens =
+        command_split(text).map_err(|e| anyhow!("Invalid command: {}\n{}", e, e.annotate(text)))?;
+    if tokens.is_empty() {
+        return Ok("No arguments".to_string());
+    }
+    let lines: Vec<_> = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| format!("{}. {:?}", i + 1, token))
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+/// Handler for command `/tokens`, which shows how a command is split into arguments
+pub async fn tokens(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let msg = tokens_text(&context.text.value)?;
+    context.send_message_in_reply(&msg).call().await?;
+    Ok(())
+}
+
+/// Returns the amounts a rendered transaction spends on expense accounts
+fn spent_amounts<'a>(txn: &'a str, sep: &str) -> Vec<(Decimal, &'a str)> {
+    txn.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let xs: Vec<_> = line.split_ascii_whitespace().collect();
+            if !has_root(xs.first()?, "Expenses", sep) {
+                return None;
+            }
+            Some((xs.get(1)?.parse().ok()?, *xs.get(2)?))
+        })
+        .collect()
+}
+
+/// Records a transaction committed by `user` as the last one, and adds it to their statistics
+/// of the month it is dated in
+fn record_commit(db: &mut Database, user: i64, txn: &str) -> Result<()> {
+    let date = transaction_date(txn).ok_or_else(|| anyhow!("Invalid transaction date"))?;
+    let spent = spent_amounts(txn, &get_config().beancount.account_separator);
+    db.add_stats(user, &date.format("%Y-%m").to_string(), &spent);
+    db.last_committed = Some(txn.to_string());
+    db.save()
+}
+
+/// Tags the committed transaction `txn` with `tag` in the ledger and commits the change,
+/// returning the tagged transaction
+fn tag_transaction(
+    repo: &impl Repo,
+    config: &Beancount,
+    txn: &str,
+    tag: &str,
+) -> Result<(String, Committed)> {
+    let header = txn.lines().next().unwrap_or_default();
+    ensure!(
+        !header
+            .split_ascii_whitespace()
+            .any(|x| x.strip_prefix('#') == Some(tag)),
+        "The last transaction is already tagged with #{}",
+        tag
+    );
+    check_repo(repo).context("Check repo failed")?;
+    let date = transaction_date(txn).ok_or_else(|| anyhow!("Invalid transaction date"))?;
+    let filename = transaction_file(&config.root, date, config.granularity);
+    let content = std::fs::read_to_string(&filename).context("Read file failed")?;
+    let (content, tagged) = add_transaction_tag(&content, txn, tag)
+        .ok_or_else(|| anyhow!("The last transaction is not in {}", filename.display()))?;
+    let write = || std::fs::write(&filename, content).context("Write file failed");
+    let message = format!("Tag a transaction with #{}", tag);
+    let committed = commit_file(repo, &filename, write, &message, None)?;
+    Ok((tagged, committed))
+}
 
+/// Handler for command `/reimbursable`, which tags the last committed transaction as
+/// reimbursable
+pub async fn reimbursable(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let txn = state
+        .read()
+        .await
+        .last_committed
+        .clone()
+        .ok_or_else(|| anyhow!("No transaction committed yet"))?;
+    let config = get_config();
+    let last = txn.clone();
+    // pulling, committing and pushing may take long, so the state is not held meanwhile
+    let (tagged, committed) = tokio::task::spawn_blocking(move || {
+        let config = &config.beancount;
+        tag_transaction(&config.repo(), config, &last, &config.reimbursable_tag)
+    })
+    .await??;
+    let msg = format!("{}\n\n{}", tagged, commit_status(&committed, "已标记✅"));
+    {
+        let mut guard = state.write().await;
+        // another transaction may have been committed meanwhile
+        if guard.last_committed.as_deref() == Some(txn.as_str()) {
+            guard.last_committed = Some(tagged);
+            guard.save()?;
+        }
+    }
+    context.send_message_in_reply(&msg).call().await?;
+    Ok(())
+}
+
+/// Handler for command `/mystats`, which replies the statistics of the transactions the user
+/// committed in a month, the current one by default
+pub async fn mystats(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let month = parse_month(&context.text.value, ledger_today(config.day_cutoff_hour))?;
+    let month = month.format("%Y-%m").to_string();
+    let user = context
+        .from
+        .as_ref()
+        .ok_or_else(|| anyhow!("Unknown user"))?
+        .id
+        .0;
+    let msg = match state.read().await.month_stats(user, &month) {
+        Some(stats) => {
+            let mut lines = vec![format!("{}: {} transactions", month, stats.transactions)];
+            for (currency, total) in stats.spent.iter() {
+                lines.push(format!("Spent {} {}", total, currency));
+            }
+            lines.join("\n")
+        }
+        None => format!("No transactions committed by you in {}", month),
+    };
+    context.send_message_in_reply(&msg).call().await?;
+    Ok(())
+}
+
+/// Handler for command `/currency`
+pub async fn currency(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let user_id = match context.from {
+        Some(ref user) => user.id.0,
+        None => return Ok(()),
+    };
+    let global = &get_config().beancount.default_currency;
+    let arg = context.text.value.trim();
+    let msg = match arg {
+        "" => {
+            let guard = state.read().await;
+            format!(
+                "Default currency: {}",
+                guard.default_currency(Some(user_id), global)
+            )
+        }
+        "reset" => {
+            let mut guard = state.write().await;
+            if guard.currencies.remove(&user_id).is_some() {
+                guard.save()?;
+            }
+            format!("Default currency reset to {}", global)
+        }
+        currency => {
+            ensure!(is_currency(currency), "Invalid currency {}", currency);
+            let mut guard = state.write().await;
+            guard.currencies.insert(user_id, currency.to_string());
+            guard.save()?;
+            format!("Default currency set to {}", currency)
+        }
+    };
+    context.send_message_in_reply(&msg).call().await?;
+    Ok(())
+}
+
+/// Handler for command `/session`, which lists the settings overridden by the user, or resets them
+/// with `/session clear`
+pub async fn session(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let user_id = match context.from {
+        Some(ref user) => user.id.0,
+        None => return Ok(()),
+    };
+    let msg = match context.text.value.trim() {
+        "" => {
+            let overrides = state.read().await.overrides(user_id);
+            if overrides.is_empty() {
+                "No overridden settings".to_string()
+            } else {
+                overrides.join("\n")
+            }
+        }
+        "clear" => {
+            let mut guard = state.write().await;
+            if guard.clear_overrides(user_id) {
+                guard.save()?;
+            }
+            "Overridden settings cleared".to_string()
+        }
+        arg => return Err(anyhow!("Unknown argument {}, usage: /session [clear]", arg)),
+    };
+    context.send_message_in_reply(&msg).call().await?;
+    Ok(())
+}
+
+/// Handler for command `/config`, which replies the effective config without the secrets
+pub async fn config(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    context
+        .send_message(&get_config().sanitized())
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Handler for command `/lockdown`, which deauthorizes everyone. `/lockdown all` also forgets
+/// the settings and statistics of the users, the review queue and the last committed transaction.
+pub async fn lockdown(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let all = match context.text.value.trim() {
+        "" => false,
+        "all" => true,
+        arg => return Err(anyhow!("Invalid argument {}, expected all", arg)),
+    };
+    let count = {
+        let mut guard = state.write().await;
+        let count = guard.lockdown(all);
+        guard.save()?;
+        count
+    };
+    warn!("Locked down, {} users deauthorized", count);
     context
-        .send_message_in_reply(&format!("{}", txn))
+        .send_message(&format!("Deauthorized {} users", count))
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Parses the transaction in the arguments of a command like `/diff` and renders it as it would
+/// be committed
+async fn command_transaction(context: &Command<Text>, state: &RwLock<Database>) -> Result<String> {
+    let global_config = get_config();
+    let config = &global_config.beancount;
+    check_repo_read(&config.repo(), config.pull_interval).context("Check repo failed")?;
+    let accounts = load_accounts(state, &config.root)
+        .await
+        .context("get accounts failed")?;
+    let mut cmds = command_split(&context.text.value).map_err(|e| {
+        anyhow!(
+            "Invalid command: {}\n{}",
+            e,
+            e.annotate(&context.text.value)
+        )
+    })?;
+    expand_shortcut(&mut cmds, &config.shortcuts);
+    let user = context.from.as_ref().map(|user| match user.username {
+        Some(ref username) => username.clone(),
+        None => user.id.0.to_string(),
+    });
+    let currency = state
+        .read()
+        .await
+        .default_currency(
+            context.from.as_ref().map(|u| u.id.0),
+            &config.default_currency,
+        )
+        .to_string();
+    let ctx = ParseContext {
+        default_payee: global_config
+            .chat(context.chat.id.0)
+            .and_then(|c| c.payee.as_deref()),
+        user: user.as_deref(),
+        ..ParseContext::new(&currency)
+    };
+    Ok(Transaction::today_from_command(&cmds, &accounts, config, &ctx)?.to_string())
+}
+
+/// Returns the file the rendered transaction `txn` is committed to, relative to the root
+fn relative_transaction_file(config: &Beancount, txn: &str) -> Result<PathBuf> {
+    let date = transaction_date(txn).ok_or_else(|| anyhow!("Invalid transaction date"))?;
+    let filename = transaction_file(&config.root, date, config.granularity);
+    Ok(filename
+        .strip_prefix(&config.root)
+        .map_or_else(|_| filename.clone(), Path::to_path_buf))
+}
+
+/// Handler for command `/where`, which shows the file a transaction would be committed to
+pub async fn target(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let txn = command_transaction(&context, &state).await?;
+    let path = relative_transaction_file(config, &txn)?;
+    context
+        .send_message_in_reply(&path.display().to_string())
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Handler for command `/diff`, which shows what committing a transaction would add to its file
+/// without committing it
+pub async fn diff(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let txn = command_transaction(&context, &state).await?;
+    let path = relative_transaction_file(config, &txn)?;
+    let filename = Path::new(&config.root).join(&path);
+    let existing = match std::fs::read_to_string(&filename) {
+        Ok(content) => Some(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("Read file failed"),
+    };
+    let diff = if config.prepend {
+        prepend_diff
+    } else {
+        append_diff
+    };
+    let diff = diff(
+        existing.as_deref(),
+        txn.trim_end(),
+        &path.display().to_string(),
+        config.blank_lines,
+    );
+    context.send_message_in_reply(&diff).call().await?;
+    Ok(())
+}
+
+/// Lists the configured currencies with their symbols and precisions, the default currency first
+fn currencies_text(config: &Beancount) -> String {
+    let mut currencies: Vec<_> = config
+        .precision
+        .keys()
+        .map(String::as_str)
+        .chain(config.currency_symbols.currencies())
+        .filter(|c| *c != config.default_currency)
+        .collect();
+    currencies.sort_unstable();
+    currencies.dedup();
+    std::iter::once(config.default_currency.as_str())
+        .chain(currencies)
+        .map(|c| {
+            let symbol = config
+                .currency_symbols
+                .get(c)
+                .map_or_else(String::new, |s| format!(" {}", s));
+            let default = if c == config.default_currency {
+                " (default)"
+            } else {
+                ""
+            };
+            match config.precision.get(c) {
+                Some(p) => format!("{}{}{}: {} decimal places", c, symbol, default, p),
+                None => format!("{}{}{}: not rounded", c, symbol, default),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Handler for command `/currencies`
+pub async fn currencies(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let text = currencies_text(&get_config().beancount);
+    context.send_message(&text).call().await?;
+    Ok(())
+}
+
+/// Handler for command `/close`
+pub async fn close(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let root = &config.root;
+    let account = context.text.value.trim();
+    check_repo(&config.repo()).context("Check repo failed")?;
+    let committed = commit_file(
+        &config.repo(),
+        &accounts_file(root),
+        || close_account(root, account, ledger_today(config.day_cutoff_hour)),
+        &format!("Close {}", account),
+        None,
+    )?;
+    refresh_accounts(&state, root)
+        .await
+        .context("get accounts failed")?;
+    let mut msg = format!("Closed {}", account);
+    if let Committed::PushFailed(e) = committed {
+        msg += &format!(
+            ", committed locally but push failed: {:#}. Use /push to retry",
+            e
+        );
+    }
+    context.send_message(&msg).call().await?;
+    Ok(())
+}
+
+/// Makes sure `root` is a git repository with an accounts file
+fn validate_root(root: &str) -> Result<()> {
+    ensure_repo(root)?;
+    ensure!(
+        accounts_file(root).is_file(),
+        "{} has no accounts file",
+        root
+    );
+    Ok(())
+}
+
+/// Checks on startup that `root` is a git repository that can be pulled and has open accounts,
+/// returning the number of accounts
+pub fn self_check(repo: &impl Repo, root: &str) -> Result<usize> {
+    validate_root(root)?;
+    check_repo(repo).context("Pulling the repository failed")?;
+    let count = get_accounts(root)
+        .context("Reading the accounts failed")?
+        .len();
+    ensure!(count > 0, "{} has no open accounts", root);
+    Ok(count)
+}
+
+/// Handler for command `/setroot`, which switches the ledger until the bot restarts
+pub async fn setroot(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let mut args = context.text.value.split_ascii_whitespace();
+    let root = args
+        .next()
+        .ok_or_else(|| anyhow!("Usage: /setroot <path> [currency]"))?;
+    let mut config = Config::clone(&get_config());
+    if let Some(currency) = args.next() {
+        ensure!(is_currency(currency), "Invalid currency {}", currency);
+        config.beancount.default_currency = currency.to_string();
+    }
+    validate_root(root)?;
+    let count = refresh_accounts(&state, root)
+        .await
+        .context("get accounts failed")?
+        .len();
+    config.beancount.root = root.to_string();
+    let msg = format!(
+        "Switched to {}, {} accounts, default currency {}",
+        root, count, config.beancount.default_currency
+    );
+    set_config(config);
+    forget_repo();
+    context.send_message(&msg).call().await?;
+    Ok(())
+}
+
+/// Longest range in days `/spent` scans
+const SPENT_MAX_DAYS: i64 = 366;
+/// Range in days `/spent` scans if not given
+const SPENT_DEFAULT_DAYS: i64 = 30;
+
+/// Splits the arguments of `/spent` into the account term and the number of days, which is given
+/// as `last N days` or just `N` after the account
+fn spent_args(text: &str) -> Result<(String, i64)> {
+    let mut xs: Vec<_> = text.split_ascii_whitespace().collect();
+    let mut days = SPENT_DEFAULT_DAYS;
+    if let [.., "last", n, "day" | "days"] = xs[..] {
+        days = n
+            .parse()
+            .with_context(|| format!("Invalid number of days {}", n))?;
+        xs.truncate(xs.len() - 3);
+    } else if let Some(n) = xs.last().and_then(|n| n.parse().ok()) {
+        days = n;
+        xs.pop();
+    }
+    ensure!(!xs.is_empty(), "Usage: /spent <account> [last N days]");
+    ensure!(
+        (1..=SPENT_MAX_DAYS).contains(&days),
+        "The number of days must be between 1 and {}",
+        SPENT_MAX_DAYS
+    );
+    Ok((xs.join(" "), days))
+}
+
+/// Handler for command `/spent`, e.g. `/spent food last 7 days`
+pub async fn spent(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let (term, days) = spent_args(&context.text.value)?;
+    check_repo_read(&config.repo(), config.pull_interval).context("Check repo failed")?;
+    let accounts = load_accounts(&state, &config.root).await?;
+    let account = resolve_account(&accounts, &term, config)?;
+    let to = ledger_today(config.day_cutoff_hour);
+    let from = to - chrono::Duration::days(days - 1);
+    let sep = &config.account_separator;
+    let totals = account_delta(&config.root, config.granularity, account, sep, from, to)
+        .context("Read file failed")?;
+    let mut lines = vec![format!("{} from {} to {}", account, from, to)];
+    if totals.is_empty() {
+        lines.push("No postings".to_string());
+    }
+    for (currency, total) in totals {
+        lines.push(format!("{} {}", total, currency));
+    }
+    context
+        .send_message_in_reply(&lines.join("\n"))
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Handler for command `/export`
+pub async fn export(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let month = parse_month(&context.text.value, ledger_today(config.day_cutoff_hour))?;
+    check_repo_read(&config.repo(), config.pull_interval).context("Check repo failed")?;
+    let content =
+        read_month(&config.root, month, config.granularity).context("Read file failed")?;
+    let month = month.format("%Y-%m");
+    if content.iter().all(u8::is_ascii_whitespace) {
+        context
+            .send_message(&format!("No transactions in {}", month))
+            .call()
+            .await?;
+    } else {
+        let name = format!("{}.bean", month);
+        context
+            .send_document(Document::with_bytes(&name, &content))
+            .call()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Handler for command `/shortcuts`
+pub async fn shortcuts(context: Arc<Command<Text>>, _state: Arc<RwLock<Database>>) -> Result<()> {
+    let mut shortcuts: Vec<_> = get_config()
+        .beancount
+        .shortcuts
+        .iter()
+        .map(|(keyword, s)| format!("{}: {} → {}", keyword, s.account, s.expense))
+        .collect();
+    let msg = if shortcuts.is_empty() {
+        "No shortcuts configured".to_string()
+    } else {
+        shortcuts.sort();
+        shortcuts.join("\n")
+    };
+    context.send_message(&msg).call().await?;
+    Ok(())
+}
+
+/// Username of the bot, fetched at startup when a command prefix is configured
+pub static BOT_USERNAME: OnceCell<String> = OnceCell::new();
+
+/// Returns the transactions in a message, or `None` if the message is not meant for the bot
+pub fn transaction_text(context: &Text) -> Option<&str> {
+    let private = matches!(context.chat.kind, chat::Kind::Private { .. });
+    strip_group_prefix(
+        &context.text.value,
+        private,
+        &get_config().bot.group_prefixes,
+        BOT_USERNAME.get().map(String::as_str),
+    )
+}
+
+/// Strips the first matched prefix off a message of a group chat. Messages of private chats, or
+/// of group chats without prefixes configured, are taken as they are. Command-like prefixes such
+/// as `/tx` may be followed by `@` and the bot's username.
+fn strip_group_prefix<'a>(
+    text: &'a str,
+    private: bool,
+    prefixes: &[String],
+    username: Option<&str>,
+) -> Option<&'a str> {
+    if private || prefixes.is_empty() {
+        return Some(text);
+    }
+    let text = text.trim_start();
+    prefixes.iter().find_map(|prefix| {
+        let rest = text.strip_prefix(prefix.as_str())?;
+        if !prefix.starts_with('/') {
+            return Some(rest);
+        }
+        let rest = match (rest.strip_prefix('@'), username) {
+            (Some(mention), Some(username)) => mention.strip_prefix(username)?,
+            (Some(_), None) => return None,
+            (None, _) => rest,
+        };
+        // `/tx` does not match `/txt`
+        rest.chars()
+            .next()
+            .is_none_or(char::is_whitespace)
+            .then_some(rest)
+    })
+}
+
+/// Reply to messages without transactions when configured to show the usage
+const USAGE: &str =
+    "Usage: <amount> <account> <expense account> [narration], e.g. 10 cash food lunch";
+
+/// Returns the reply to a message without transactions, if any
+fn empty_reply(behavior: EmptyMessage) -> Option<&'static str> {
+    match behavior {
+        EmptyMessage::Ignore => None,
+        EmptyMessage::Usage => Some(USAGE),
+    }
+}
+
+/// Handler for messages
+pub async fn command(context: &Text, text: &str, state: Arc<RwLock<Database>>) -> Result<()> {
+    let accounts = load_accounts(&state, &get_config().beancount.root)
+        .await
+        .context("get accounts failed")?;
+    let user = context.from.as_ref().map(|user| match user.username {
+        Some(ref username) => username.clone(),
+        None => user.id.0.to_string(),
+    });
+    let global_config = get_config();
+    let config = &global_config.beancount;
+    let currency = state
+        .read()
+        .await
+        .default_currency(
+            context.from.as_ref().map(|u| u.id.0),
+            &config.default_currency,
+        )
+        .to_string();
+    let ctx = ParseContext {
+        default_payee: global_config
+            .chat(context.chat.id.0)
+            .and_then(|c| c.payee.as_deref()),
+        user: user.as_deref(),
+        ..ParseContext::new(&currency)
+    };
+    let mut cmds = command_split_lines(text, config.comment_lines)?;
+    if cmds.is_empty() {
+        if let Some(msg) = empty_reply(get_config().bot.empty_message) {
+            context.send_message_in_reply(msg).call().await?;
+        }
+        return Ok(());
+    }
+    for (_, cmd) in cmds.iter_mut() {
+        expand_shortcut(cmd, &get_config().beancount.shortcuts);
+    }
+    // an amount replying to a transaction reuses its accounts
+    let prev = match (context.reply_to.as_ref(), cmds.as_slice()) {
+        (Some(reply), [(_, cmd)]) if cmd.len() == 1 => match reply.kind {
+            Kind::Text(ref text) => {
+                let state = state.read().await;
+                Some(shown_transaction(
+                    &state,
+                    config,
+                    reply.chat.id,
+                    reply.id,
+                    &text.value,
+                )?)
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    let txns = match (prev.as_deref(), cmds.as_slice()) {
+        (Some(prev), [(_, cmd)]) => {
+            vec![Transaction::from_previous(
+                prev, &cmd[0], &accounts, config, &ctx,
+            )?]
+        }
+        // parse all the lines before sending any preview, so that an invalid line does not
+        // leave the other transactions half-previewed
+        _ => cmds
+            .iter()
+            .map(|(lineno, cmd)| {
+                Transaction::today_from_command(cmd, &accounts, config, &ctx)
+                    .with_context(|| anyhow!("Invalid transaction on line {}", lineno))
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+    for txn in txns.iter() {
+        if auto_commits(config, txn) {
+            commit_directly(context, &state, txn).await?;
+        } else {
+            send_preview(context, &state, txn).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Determines whether a transaction is committed without confirmation, which requires all its
+/// accounts to be matched unambiguously and no amount to need another confirmation
+fn auto_commits(config: &Beancount, txn: &Transaction<'_, '_>) -> bool {
+    config.auto_commit
+        && txn.flag() == FLAG_COMPLETE
+        && config
+            .confirm_above
+            .is_none_or(|threshold| amount_above(&txn.to_string(), threshold.into()).is_none())
+}
+
+/// Commits `txn` by [`commit_transaction`] on the blocking thread pool, since pulling, checking
+/// and pushing the ledger may take long
+async fn commit_blocking(
+    config: Arc<Config>,
+    txn: String,
+    orig_cmd: Option<String>,
+) -> Result<Committed> {
+    tokio::task::spawn_blocking(move || {
+        let config = &config.beancount;
+        commit_transaction(&config.repo(), config, &txn, orig_cmd.as_deref())
+    })
+    .await?
+}
+
+/// Commits a transaction without confirmation, replying it along with the status
+async fn commit_directly(
+    context: &Text,
+    state: &RwLock<Database>,
+    txn: &Transaction<'_, '_>,
+) -> Result<()> {
+    let config = get_config();
+    let mut text = txn.to_string();
+    if config.beancount.message_link {
+        let link = message_link(context.chat.id.0, context.message_id.0);
+        text = add_transaction_metadata(&text, "telegram-message", &link);
+    }
+    let text = text.trim_end();
+    let orig_cmd = Some(context.text.value.clone());
+    let committed = commit_blocking(config, text.to_string(), orig_cmd).await?;
+    if let Some(user) = context.from.as_ref() {
+        if let Err(e) = record_commit(&mut *state.write().await, user.id.0, text) {
+            warn!("Failed to record the committed transaction: {:?}", e);
+        }
+    }
+    let msg = format!("{}\n\n{}", text, commit_status(&committed, "已自动提交✅"));
+    let msg = context.send_message_in_reply(&msg).call().await?;
+    state
+        .write()
+        .await
+        .add_previewed(msg.chat.id, msg.id, text.to_string());
+    Ok(())
+}
+
+/// Handler for command `/opening`
+pub async fn opening(context: Arc<Command<Text>>, state: Arc<RwLock<Database>>) -> Result<()> {
+    let config = &get_config().beancount;
+    let accounts = load_accounts(&state, &config.root)
+        .await
+        .context("get accounts failed")?;
+    let cmds = command_split(&context.text.value).map_err(|e| {
+        anyhow!(
+            "Invalid command: {}\n{}",
+            e,
+            e.annotate(&context.text.value)
+        )
+    })?;
+    let currency = state
+        .read()
+        .await
+        .default_currency(
+            context.from.as_ref().map(|u| u.id.0),
+            &config.default_currency,
+        )
+        .to_string();
+    let txn = Transaction::opening_balance_from_command(&cmds, &accounts, &currency, config)?;
+    send_preview(&*context, &state, &txn).await
+}
+
+/// Returns the transaction shown in `text` of `message` in `chat`. Previews may be localized, so
+/// the transactions they were rendered from are committed instead; other messages show the
+/// transactions as they are.
+fn shown_transaction(
+    state: &Database,
+    config: &Beancount,
+    chat: chat::Id,
+    message: message::Id,
+    text: &str,
+) -> Result<String> {
+    if let Some(txn) = state.previewed(chat, message) {
+        return Ok(txn.to_string());
+    }
+    ensure!(
+        config.currency_symbols.is_empty() && config.preview_date_format.is_none(),
+        "The transaction of this message is no longer known, please send it again"
+    );
+    // notes in the preview are not part of the transaction
+    Ok(preview_transaction(text).to_string())
+}
+
+/// Replies a transaction preview with a confirmation keyboard
+async fn send_preview(
+    context: &impl ChatMethods,
+    state: &RwLock<Database>,
+    txn: &Transaction<'_, '_>,
+) -> Result<()> {
+    let keyboard = vec![
+        Button::new("提交", ButtonKind::CallbackData(Action::Commit.data())),
+        Button::new("取消", ButtonKind::CallbackData(Action::Cancel.data())),
+    ];
+    let msg = context
+        .send_message_in_reply(&localize_preview(&txn.preview(), &get_config().beancount))
         .reply_markup(&[keyboard.as_slice()][..])
         .call()
         .await?;
+    let mut guard = state.write().await;
+    guard.add_previewed(msg.chat.id, msg.id, txn.to_string());
+    if let (Some(_), Kind::Text(ref txt)) = (get_config().bot.confirm_timeout, &msg.kind) {
+        guard.previews.push(Preview {
+            chat: msg.chat.id,
+            message: msg.id,
+            text: txt.value.clone(),
+            date: msg.date,
+        });
+    }
     Ok(())
 }
 
+const EXPIRED: &str = "已过期⌛";
+
+/// Periodically marks the previews older than `timeout` seconds as expired, removing their
+/// keyboards
+pub async fn expire_previews(bot: tbot::Bot, state: Arc<RwLock<Database>>, timeout: i64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        let expired = state.write().await.take_expired_previews(timeout);
+        for preview in expired.iter() {
+            // editing the text without a reply markup removes the keyboard
+            let r = bot
+                .edit_message_text(
+                    preview.chat,
+                    preview.message,
+                    &format!("{}\n\n{}", preview.text, EXPIRED),
+                )
+                .call()
+                .await;
+            if let Err(e) = r {
+                warn!("Failed to expire preview: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Periodically pushes the commits deferred by squashing once the squash `window` of the last
+/// one has passed
+pub async fn push_deferred(window: i64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(window.clamp(1, 30) as u64));
+    loop {
+        interval.tick().await;
+        let config = get_config();
+        let repo = config.beancount.repo();
+        let r = match repo.push_due() {
+            Ok(true) => push_repo(&repo),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = r {
+            warn!("Failed to push deferred commits: {:?}", e);
+        }
+    }
+}
+
+/// Appends the rendered transaction `txn` to the transaction file of the ledger and commits it
+fn commit_transaction(
+    repo: &impl Repo,
+    config: &Beancount,
+    txn: &str,
+    orig_cmd: Option<&str>,
+) -> Result<Committed> {
+    check_repo(repo).context("Check repo failed")?;
+    if config.revalidate_accounts {
+        let accounts = get_accounts(&config.root).context("get accounts failed")?;
+        let missing = missing_accounts(txn, &accounts);
+        ensure!(
+            missing.is_empty(),
+            "Account {} is no longer opened, please enter the transaction again",
+            missing.join(", ")
+        );
+    }
+    let date = transaction_date(txn).ok_or_else(|| anyhow!("Invalid transaction date"))?;
+    let filename = transaction_file(&config.root, date, config.granularity);
+    let write = || {
+        if config.prepend {
+            prepend_to_file(txn, &filename, config.blank_lines)
+                .context("Prepend to file failed")?;
+        } else {
+            append_to_file(txn, &filename, config.blank_lines).context("Append to file failed")?;
+        }
+        if config.validate_before_commit {
+            let main = Path::new(&config.root).join(&config.main_file);
+            // the file is restored by `commit_file` if the ledger fails the check
+            bean_check(&config.bean_check, &main)
+                .map_err(|e| e.context("The transaction is not committed"))?;
+        }
+        Ok(())
+    };
+    commit_file(repo, &filename, write, "Add a transaction", orig_cmd)
+}
+
+/// Returns `status` of a committed transaction, or the reason if pushing the commit failed
+fn commit_status(committed: &Committed, status: &'static str) -> Cow<'static, str> {
+    match committed {
+        Committed::Pushed | Committed::Deferred => status.into(),
+        Committed::PushFailed(e) => {
+            format!("已在本地提交，推送失败：{:#}，可用 /push 重试⚠️", e).into()
+        }
+    }
+}
+
+/// Returns the link to a message, or its chat and message ids if the chat has no message links
+fn message_link(chat: i64, message: u32) -> String {
+    // ids of supergroups and channels are the ids in their links prefixed by -100
+    match chat.checked_neg().map(|id| id - 1_000_000_000_000) {
+        Some(id) if id > 0 => format!("https://t.me/c/{}/{}", id, message),
+        _ => format!("{}/{}", chat, message),
+    }
+}
+
 /// Handler for commit confirmation
-pub async fn confirm(context: Arc<DataCallback>, _state: Arc<RwLock<Database>>) -> Result<()> {
-    let root = &get_config().beancount.root;
+pub async fn confirm(context: Arc<DataCallback>, state: Arc<RwLock<Database>>) -> Result<()> {
+    // buttons of `/find` send the account
+    if let Some(account) = context.data.strip_prefix(FIND_PREFIX) {
+        if let Origin::Message(ref origin) = context.origin {
+            context
+                .bot
+                .send_message(origin.chat.id, account)
+                .call()
+                .await?;
+        }
+        return Ok(());
+    }
+    let action = match Action::from_data(&context.data) {
+        Some(action) => action,
+        None => {
+            warn!("Ignoring unknown callback data: {}", context.data);
+            return Ok(());
+        }
+    };
+    let global_config = get_config();
+    let config = &global_config.beancount;
     if let Origin::Message(ref origin) = context.origin {
         if let Kind::Text(ref txt) = origin.kind {
-            let msg = match context.data.as_str() {
-                "commit" => {
-                    check_repo(root).context("Check repo failed")?;
-                    // start of txt.value is YYYY-MM-DD.
-                    // filename = {root}/txs/{year}/{month}.bean
-                    let filename = PathBuf::from(root)
-                        .join("txs")
-                        .join(&txt.value[..4])
-                        .join(format!("{}.bean", &txt.value[5..7]));
-                    append_to_file(&txt.value, &filename).context("Append to file failed")?;
-                    let orig_cmd =
-                        if let Some(Kind::Text(t)) = origin.reply_to.as_ref().map(|rt| &rt.kind) {
-                            Some(t.value.as_str())
-                        } else {
-                            None
-                        };
-                    commit_file(root, &filename, orig_cmd).context("Commit file failed")?;
-                    "已提交✅"
+            state
+                .write()
+                .await
+                .previews
+                .retain(|p| p.chat != origin.chat.id || p.message != origin.id);
+            if matches!(
+                action,
+                Action::Commit | Action::CommitLarge | Action::Cancel
+            ) && !get_config().bot.accepts_confirmation(elapsed(origin.date))
+            {
+                context
+                    .bot
+                    .edit_message_text(
+                        origin.chat.id,
+                        origin.id,
+                        &format!("{}\n\n{}", txt.value, EXPIRED),
+                    )
+                    .call()
+                    .await?;
+                return Ok(());
+            }
+            let orig_cmd = if let Some(Kind::Text(t)) = origin.reply_to.as_ref().map(|rt| &rt.kind)
+            {
+                Some(t.value.as_str())
+            } else {
+                None
+            };
+            let commit = matches!(action, Action::Commit | Action::CommitLarge);
+            let mut txn = if commit {
+                let state = state.read().await;
+                shown_transaction(&state, config, origin.chat.id, origin.id, &txt.value)?
+            } else {
+                // the messages of `/review` show the queued transactions as they are
+                preview_transaction(&txt.value).to_string()
+            };
+            if let (Action::Commit, Some(threshold)) = (action, config.confirm_above) {
+                if let Some(amount) = amount_above(&txn, threshold.into()) {
+                    let text = format!("{}\n\n{} — 金额无误吗？", txt.value, amount);
+                    let keyboard = vec![
+                        Button::new("确认", ButtonKind::CallbackData(Action::CommitLarge.data())),
+                        Button::new("取消", ButtonKind::CallbackData(Action::Cancel.data())),
+                    ];
+                    context
+                        .bot
+                        .edit_message_text(origin.chat.id, origin.id, &text)
+                        .reply_markup(Keyboard::from(&[keyboard.as_slice()][..]))
+                        .call()
+                        .await?;
+                    if get_config().bot.confirm_timeout.is_some() {
+                        state.write().await.previews.push(Preview {
+                            chat: origin.chat.id,
+                            message: origin.id,
+                            text,
+                            date: origin.date,
+                        });
+                    }
+                    return Ok(());
+                }
+            }
+            if commit && config.message_link {
+                let message = origin.reply_to.as_ref().map_or(origin.id, |rt| rt.id);
+                let link = message_link(origin.chat.id.0, message.0);
+                txn = add_transaction_metadata(&txn, "telegram-message", &link);
+            }
+            let txn = txn.as_str();
+            let msg = match action {
+                _ if commit && transaction_flag(txn) == Some(FLAG_INCOMPLETE) => {
+                    let mut guard = state.write().await;
+                    guard.review_queue.push(ReviewEntry {
+                        txn: txn.to_string(),
+                        cmd: orig_cmd.map(ToString::to_string),
+                    });
+                    guard.save()?;
+                    "已加入审核队列⚠️".into()
+                }
+                Action::Commit | Action::CommitLarge => {
+                    let orig_cmd = orig_cmd.map(ToString::to_string);
+                    let committed =
+                        commit_blocking(Arc::clone(&global_config), txn.to_string(), orig_cmd)
+                            .await?;
+                    if let Err(e) = record_commit(&mut *state.write().await, context.from.id.0, txn)
+                    {
+                        warn!("Failed to record the committed transaction: {:?}", e);
+                    }
+                    commit_status(&committed, "已提交✅")
+                }
+                Action::Cancel => "已取消❌".into(),
+                Action::Approve => {
+                    // the entry is taken out of the queue while committing without holding the
+                    // state, so that approving it again meanwhile fails
+                    let (idx, entry) = state
+                        .write()
+                        .await
+                        .take_review(txn)
+                        .ok_or_else(|| anyhow!("Transaction is not in the review queue"))?;
+                    let approved = set_transaction_flag(&entry.txn, FLAG_COMPLETE)
+                        .ok_or_else(|| anyhow!("Invalid transaction in the review queue"))?;
+                    let committed = match commit_blocking(
+                        Arc::clone(&global_config),
+                        approved.clone(),
+                        entry.cmd.clone(),
+                    )
+                    .await
+                    {
+                        Ok(committed) => committed,
+                        Err(e) => {
+                            let mut guard = state.write().await;
+                            guard.restore_review(idx, entry);
+                            guard.save()?;
+                            return Err(e);
+                        }
+                    };
+                    if let Err(e) =
+                        record_commit(&mut *state.write().await, context.from.id.0, &approved)
+                    {
+                        warn!("Failed to record the committed transaction: {:?}", e);
+                    }
+                    commit_status(&committed, "已批准✅")
+                }
+                Action::Push => {
+                    let config = Arc::clone(&global_config);
+                    tokio::task::spawn_blocking(move || push_repo(&config.beancount.repo()))
+                        .await??;
+                    "已推送✅".into()
+                }
+                Action::Discard => {
+                    let mut guard = state.write().await;
+                    if guard.take_review(txn).is_some() {
+                        guard.save()?;
+                    }
+                    "已丢弃🗑️".into()
                 }
-                "cancel" => "已取消❌",
-                s => unreachable!("undefined message: {}", s),
             };
             context
                 .bot
@@ -119,3 +1428,620 @@ pub async fn confirm(context: Arc<DataCallback>, _state: Arc<RwLock<Database>>)
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_data() {
+        for &action in ALL_ACTIONS.iter() {
+            assert_eq!(Action::from_data(action.data()), Some(action));
+        }
+        assert_eq!(Action::from_data("unknown"), None);
+        assert_eq!(Action::from_data(""), None);
+    }
+
+    #[test]
+    fn test_message_link() {
+        assert_eq!(
+            message_link(-1001234567890, 42),
+            "https://t.me/c/1234567890/42"
+        );
+        assert_eq!(message_link(-1000000000001, 7), "https://t.me/c/1/7");
+        // private chats and basic groups
+        assert_eq!(message_link(12345678, 42), "12345678/42");
+        assert_eq!(message_link(-987654321, 42), "-987654321/42");
+        assert_eq!(message_link(-1000000000000, 42), "-1000000000000/42");
+    }
+
+    /// [`Repo`] recording the operations on it
+    #[derive(Default)]
+    struct MockRepo {
+        ops: std::cell::RefCell<Vec<String>>,
+        fail_push: bool,
+    }
+
+    impl Repo for MockRepo {
+        fn pull(&self) -> Result<()> {
+            self.ops.borrow_mut().push("pull".to_string());
+            Ok(())
+        }
+        fn add(&self, file: &std::path::Path) -> Result<()> {
+            let name = file.file_name().unwrap().to_string_lossy();
+            self.ops.borrow_mut().push(format!("add {}", name));
+            Ok(())
+        }
+        fn commit(&self, message: &str, orig_cmd: Option<&str>) -> Result<()> {
+            let op = format!("commit {} / {}", message, orig_cmd.unwrap_or(""));
+            self.ops.borrow_mut().push(op);
+            Ok(())
+        }
+        fn push(&self) -> Result<()> {
+            self.ops.borrow_mut().push("push".to_string());
+            anyhow::ensure!(!self.fail_push, "git push failed");
+            Ok(())
+        }
+        fn unstage(&self, file: &std::path::Path) -> Result<()> {
+            let name = file.file_name().unwrap().to_string_lossy();
+            self.ops.borrow_mut().push(format!("unstage {}", name));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_commit_transaction() {
+        let root = crate::utils::temp_dir("commit_transaction");
+        let config: Beancount = toml::from_str(&format!(
+            "root = {:?}\ndefault_currency = \"CNY\"",
+            root.to_str().unwrap()
+        ))
+        .unwrap();
+        let txn = "2021-03-05 * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY";
+        let repo = MockRepo::default();
+        commit_transaction(&repo, &config, txn, Some("10 cash food lunch")).unwrap();
+        assert_eq!(
+            *repo.ops.borrow(),
+            [
+                "pull",
+                "add 03.bean",
+                "commit Add a transaction / 10 cash food lunch",
+                "push"
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("txs/2021/03.bean")).unwrap(),
+            format!("{}\n", txn)
+        );
+
+        let repo = MockRepo {
+            fail_push: true,
+            ..Default::default()
+        };
+        // the transaction is committed even though the push fails
+        let committed = commit_transaction(&repo, &config, txn, None).unwrap();
+        match committed {
+            Committed::PushFailed(ref e) => assert_eq!(e.to_string(), "git push failed"),
+            _ => panic!("unexpected outcome {:?}", committed),
+        }
+        assert_eq!(repo.ops.borrow().last().unwrap(), "push");
+        assert!(commit_status(&committed, "已提交✅").contains("git push failed"));
+        assert_eq!(commit_status(&Committed::Pushed, "已提交✅"), "已提交✅");
+        assert!(commit_transaction(&repo, &config, "invalid", None).is_err());
+    }
+
+    #[test]
+    fn test_validate_before_commit() {
+        let root = crate::utils::temp_dir("validate_before_commit");
+        let config = |bean_check: &str| -> Beancount {
+            toml::from_str(&format!(
+                "root = {:?}\ndefault_currency = \"CNY\"\nvalidate_before_commit = true\n\
+                 bean_check = {:?}",
+                root.to_str().unwrap(),
+                bean_check
+            ))
+            .unwrap()
+        };
+        let file = root.join("txs/2021/03.bean");
+        let lunch = "2021-03-05 * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY";
+        let dinner = "2021-03-05 * \"dinner\"\n    Expenses:Food 20 CNY\n    Assets:Cash -20 CNY";
+
+        // the new file is removed
+        let repo = MockRepo::default();
+        let err = commit_transaction(&repo, &config("false"), lunch, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("bean-check failed"));
+        assert!(!file.exists());
+        assert_eq!(*repo.ops.borrow(), ["pull", "unstage 03.bean"]);
+
+        commit_transaction(&MockRepo::default(), &config("true"), lunch, None).unwrap();
+        let committed = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(committed, format!("{}\n", lunch));
+
+        // the existing file is restored
+        let repo = MockRepo::default();
+        assert!(commit_transaction(&repo, &config("false"), dinner, None).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), committed);
+        assert_eq!(*repo.ops.borrow(), ["pull", "unstage 03.bean"]);
+
+        let err =
+            commit_transaction(&repo, &config("no-such-bean-check"), dinner, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("not found"));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), committed);
+    }
+
+    #[test]
+    fn test_revalidate_accounts() {
+        let root = crate::utils::temp_dir("revalidate_accounts");
+        std::fs::write(
+            root.join("accounts.bean"),
+            "2021-01-01 open Assets:Cash\n2021-01-01 open Expenses:Food\n",
+        )
+        .unwrap();
+        let config: Beancount = toml::from_str(&format!(
+            "root = {:?}\ndefault_currency = \"CNY\"\nrevalidate_accounts = true",
+            root.to_str().unwrap()
+        ))
+        .unwrap();
+        let txn = "2021-03-05 * \"lunch\"\n    telegram-message: \"1/2\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY";
+        let repo = MockRepo::default();
+        commit_transaction(&repo, &config, txn, None).unwrap();
+
+        // the account is closed between the preview and the confirmation
+        close_account(
+            &root,
+            "Expenses:Food",
+            chrono::NaiveDate::from_ymd(2021, 3, 6),
+        )
+        .unwrap();
+        let repo = MockRepo::default();
+        let err = commit_transaction(&repo, &config, txn, None).unwrap_err();
+        assert!(err.to_string().contains("Expenses:Food"));
+        assert_eq!(*repo.ops.borrow(), ["pull"]);
+        assert_eq!(
+            std::fs::read_to_string(root.join("txs/2021/03.bean")).unwrap(),
+            format!("{}\n", txn)
+        );
+    }
+
+    #[test]
+    fn test_relative_transaction_file() {
+        let accounts = vec!["Assets:Cash".to_string(), "Expenses:Food".to_string()];
+        let config: Beancount = toml::from_str(
+            "root = \"/ledger/\"\ndefault_currency = \"CNY\"\ngranularity = \"day\"",
+        )
+        .unwrap();
+        let cmds = command_split("2021-03-05 10 cash food lunch").unwrap();
+        let txn =
+            Transaction::today_from_command(&cmds, &accounts, &config, &ParseContext::new("CNY"))
+                .unwrap()
+                .to_string();
+        assert_eq!(
+            relative_transaction_file(&config, &txn).unwrap(),
+            Path::new("txs/2021/03/05.bean")
+        );
+        let config = Beancount {
+            granularity: crate::Granularity::Month,
+            ..config
+        };
+        assert_eq!(
+            relative_transaction_file(&config, &txn).unwrap(),
+            Path::new("txs/2021/03.bean")
+        );
+        assert!(relative_transaction_file(&config, "invalid").is_err());
+    }
+
+    #[test]
+    fn test_whoami_text() {
+        let user: User = serde_json::from_str(
+            r#"{"id": 42, "is_bot": false, "first_name": "Alice", "username": "alice"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            whoami_text(&user, true, false),
+            "User id: 42\nUsername: @alice\nAuthorized: yes\nAdmin: no"
+        );
+        assert_eq!(
+            whoami_text(&user, true, true),
+            "User id: 42\nUsername: @alice\nAuthorized: yes\nAdmin: yes"
+        );
+        assert_eq!(
+            whoami_text(&user, false, false),
+            "User id: 42\nUsername: @alice\nAuthorized: no\nAdmin: no"
+        );
+
+        let user: User =
+            serde_json::from_str(r#"{"id": 7, "is_bot": false, "first_name": "Bob"}"#).unwrap();
+        assert_eq!(
+            whoami_text(&user, false, false),
+            "User id: 7\nUsername: <none>\nAuthorized: no\nAdmin: no"
+        );
+    }
+
+    #[test]
+    fn test_accounts_text() {
+        let accounts = vec!["Assets:Cash".to_string(), "Expenses:Food".to_string()];
+        assert_eq!(
+            accounts_text(&accounts, &["a"], None),
+            "Assets:Cash Expenses:Food"
+        );
+        assert_eq!(
+            accounts_text(&[], &["xyz", "abc"], None),
+            "No accounts matching xyz abc"
+        );
+        assert_eq!(accounts_text(&[], &[], Some(1)), "No accounts opened");
+
+        // truncated only beyond the limit
+        assert_eq!(
+            accounts_text(&accounts, &[], Some(2)),
+            "Assets:Cash Expenses:Food"
+        );
+        assert_eq!(
+            accounts_text(&accounts, &[], Some(1)),
+            "Assets:Cash\n...and 1 more, refine your search"
+        );
+    }
+
+    #[test]
+    fn test_accounts_as_file() {
+        let accounts = vec!["Assets:Cash".to_string(), "Expenses:Food".to_string()];
+        assert!(!accounts_as_file(&accounts, false));
+        assert!(accounts_as_file(&accounts, true));
+        assert!(!accounts_as_file(&[], false));
+
+        // each account takes its length and a separator
+        let accounts = vec!["Expenses:Food".to_string(); ACCOUNTS_INLINE_LIMIT / 14];
+        assert!(!accounts_as_file(&accounts, false));
+        let accounts = vec!["Expenses:Food".to_string(); ACCOUNTS_INLINE_LIMIT / 14 + 1];
+        assert!(accounts_as_file(&accounts, false));
+    }
+
+    #[test]
+    fn test_tag_transaction() {
+        let root = crate::utils::temp_dir("tag_transaction");
+        let config: Beancount = toml::from_str(&format!(
+            "root = {:?}\ndefault_currency = \"CNY\"",
+            root.to_str().unwrap()
+        ))
+        .unwrap();
+        let lunch = "2021-03-05 * \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY";
+        let taxi = "2021-03-05 * \"taxi\"\n    Expenses:Transport 10 CNY\n    Assets:Cash -10 CNY";
+        let file = root.join("txs/2021/03.bean");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, format!("{}\n\n{}\n\n{}\n", lunch, taxi, lunch)).unwrap();
+
+        let repo = MockRepo::default();
+        let (tagged, _) = tag_transaction(&repo, &config, taxi, "reimbursable").unwrap();
+        let expected = taxi.replacen("\"taxi\"", "\"taxi\" #reimbursable", 1);
+        assert_eq!(tagged, expected);
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            format!("{}\n\n{}\n\n{}\n", lunch, expected, lunch)
+        );
+        assert_eq!(
+            *repo.ops.borrow(),
+            [
+                "pull",
+                "add 03.bean",
+                "commit Tag a transaction with #reimbursable / ",
+                "push"
+            ]
+        );
+
+        // the last of identical transactions is tagged
+        tag_transaction(&repo, &config, lunch, "work").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            format!(
+                "{}\n\n{}\n\n{}\n",
+                lunch,
+                expected,
+                lunch.replacen("\"lunch\"", "\"lunch\" #work", 1)
+            )
+        );
+        assert_eq!(
+            tag_transaction(&repo, &config, &tagged, "reimbursable")
+                .unwrap_err()
+                .to_string(),
+            "The last transaction is already tagged with #reimbursable"
+        );
+        let missing = lunch.replace("lunch", "dinner");
+        assert!(tag_transaction(&repo, &config, &missing, "work").is_err());
+    }
+
+    #[test]
+    fn test_group_prefix() {
+        let text = "10 cash food lunch";
+        let dollar = ["$".to_string()];
+        let both = ["$".to_string(), "/tx".to_string()];
+        assert_eq!(strip_group_prefix(text, true, &[], None), Some(text));
+        assert_eq!(strip_group_prefix(text, false, &[], None), Some(text));
+        assert_eq!(strip_group_prefix(text, true, &dollar, None), Some(text));
+        assert_eq!(strip_group_prefix(text, false, &dollar, None), None);
+        assert_eq!(
+            strip_group_prefix(" $10 cash food lunch", false, &dollar, None),
+            Some(text)
+        );
+        assert_eq!(
+            strip_group_prefix("$ 10 cash food\n20 cash food", false, &dollar, None),
+            Some(" 10 cash food\n20 cash food")
+        );
+        assert_eq!(
+            strip_group_prefix("$10 cash food lunch", true, &dollar, None),
+            Some("$10 cash food lunch")
+        );
+
+        let split = |text| {
+            let text = strip_group_prefix(text, false, &both, Some("beanbot")).unwrap();
+            command_split(text).unwrap()
+        };
+        assert_eq!(
+            split("$10 cash food lunch"),
+            split("/tx 10 cash food lunch")
+        );
+        assert_eq!(
+            split("/tx@beanbot 10 cash food lunch"),
+            split("$10 cash food lunch")
+        );
+        assert_eq!(
+            strip_group_prefix("/tx@otherbot 10 cash", false, &both, Some("beanbot")),
+            None
+        );
+        assert_eq!(
+            strip_group_prefix("/tx@beanbot 10 cash", false, &both, None),
+            None
+        );
+        assert_eq!(strip_group_prefix("/txt 10 cash", false, &both, None), None);
+        assert_eq!(strip_group_prefix("/tx", false, &both, None), Some(""));
+    }
+
+    #[test]
+    fn test_validate_root() {
+        let root = crate::utils::temp_dir("validate_root");
+        let path = root.to_str().unwrap();
+        std::fs::write(root.join("accounts.bean"), "2021-01-01 open Assets:Cash\n").unwrap();
+        assert_eq!(
+            validate_root(path).unwrap_err().to_string(),
+            format!("{} is not a git repository", path)
+        );
+
+        let st = std::process::Command::new("git")
+            .args(["init", "-q", path])
+            .status()
+            .unwrap();
+        assert!(st.success());
+        validate_root(path).unwrap();
+        std::fs::remove_file(root.join("accounts.bean")).unwrap();
+        assert_eq!(
+            validate_root(path).unwrap_err().to_string(),
+            format!("{} has no accounts file", path)
+        );
+    }
+
+    #[test]
+    fn test_self_check() {
+        let root = crate::utils::temp_dir("self_check");
+        let path = root.to_str().unwrap();
+        let repo = MockRepo::default();
+        assert_eq!(
+            self_check(&repo, path).unwrap_err().to_string(),
+            format!("{} is not a git repository", path)
+        );
+
+        let st = std::process::Command::new("git")
+            .args(["init", "-q", path])
+            .status()
+            .unwrap();
+        assert!(st.success());
+        std::fs::write(root.join("accounts.bean"), "; no accounts yet\n").unwrap();
+        assert_eq!(
+            self_check(&repo, path).unwrap_err().to_string(),
+            format!("{} has no open accounts", path)
+        );
+        std::fs::write(
+            root.join("accounts.bean"),
+            "2021-01-01 open Assets:Cash\n2021-01-01 open Expenses:Food\n",
+        )
+        .unwrap();
+        assert_eq!(self_check(&repo, path).unwrap(), 2);
+        assert_eq!(*repo.ops.borrow(), ["pull", "pull"]);
+    }
+
+    #[test]
+    fn test_empty_message() {
+        let cmds = command_split_lines("  \n\t\n", false).unwrap();
+        assert!(cmds.is_empty());
+        assert_eq!(empty_reply(EmptyMessage::Ignore), None);
+        assert_eq!(empty_reply(EmptyMessage::Usage), Some(USAGE));
+        // comments only are empty too
+        assert!(command_split_lines("; lunch", true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_spent_amounts() {
+        let txn = "2021-03-05 * \"lunch\"\n    entered-by: \"bob\"\n    Expenses:Food 10 CNY\n    Expenses:Food:Snack 2.5 USD @ 7 CNY\n    Assets:Cash -27.5 CNY";
+        assert_eq!(
+            spent_amounts(txn, ":"),
+            [(Decimal::new(10, 0), "CNY"), (Decimal::new(25, 1), "USD")]
+        );
+        let txn = "2021-03-05 * \"salary\"\n    Assets:Bank 100 CNY\n    Income:Salary -100 CNY";
+        assert!(spent_amounts(txn, ":").is_empty());
+    }
+
+    #[test]
+    fn test_spent_args() {
+        assert_eq!(spent_args("food").unwrap(), ("food".to_string(), 30));
+        assert_eq!(
+            spent_args("eat out last 7 days").unwrap(),
+            ("eat out".to_string(), 7)
+        );
+        assert_eq!(spent_args("food 14").unwrap(), ("food".to_string(), 14));
+        assert!(spent_args("last 7 days").is_err());
+        assert!(spent_args("food last 0 days").is_err());
+        assert!(spent_args("food 1000").is_err());
+    }
+
+    #[test]
+    fn test_tokens_text() {
+        assert_eq!(
+            tokens_text(r#"10 cash food "lunch with \"Bob\"" 'it''s'"#).unwrap(),
+            "1. \"10\"\n2. \"cash\"\n3. \"food\"\n4. \"lunch with \\\"Bob\\\"\"\n5. \"its\""
+        );
+        assert_eq!(tokens_text("  ").unwrap(), "No arguments");
+        let err = tokens_text("10 cash 'lunch").unwrap_err().to_string();
+        assert!(err.starts_with("Invalid command: "));
+        assert!(err.ends_with("\n10 cash 'lunch\n        ^"));
+    }
+
+    #[test]
+    fn test_shown_transaction() {
+        let config: Beancount = toml::from_str(
+            "root = \"\"\ndefault_currency = \"CNY\"\n[currency_symbols]\nUSD = \"$\"",
+        )
+        .unwrap();
+        let (chat, message) = (chat::Id(1), message::Id(2));
+        let txn = "2021-03-05 * \"$5 off\"\n    Expenses:Food 5 USD\n    Assets:Cash -5 USD";
+        let preview = localize_preview(txn, &config);
+        assert_ne!(preview, txn);
+        let mut db = Database::default();
+        assert!(shown_transaction(&db, &config, chat, message, &preview).is_err());
+        db.add_previewed(chat, message, txn.to_string());
+        assert_eq!(
+            shown_transaction(&db, &config, chat, message, &preview).unwrap(),
+            txn
+        );
+        assert!(shown_transaction(&db, &config, chat::Id(2), message, &preview).is_err());
+        let config: Beancount = toml::from_str(
+            "root = \"\"\ndefault_currency = \"CNY\"\npreview_date_format = \"%Y年%-m月%-d日\"",
+        )
+        .unwrap();
+        let preview = localize_preview(txn, &config);
+        assert!(preview.starts_with("2021年3月5日 * "));
+        assert!(shown_transaction(&Database::default(), &config, chat, message, &preview).is_err());
+        assert_eq!(
+            shown_transaction(&db, &config, chat, message, &preview).unwrap(),
+            txn
+        );
+
+        // without symbols the message shows the transaction
+        let config: Beancount = toml::from_str("root = \"\"\ndefault_currency = \"CNY\"").unwrap();
+        let text = format!("{}\n\n已提交✅", txn);
+        assert_eq!(
+            shown_transaction(&Database::default(), &config, chat, message, &text).unwrap(),
+            txn
+        );
+    }
+
+    #[test]
+    fn test_currencies_text() {
+        let config: Beancount = toml::from_str(
+            "root = \"\"\ndefault_currency = \"CNY\"\n[precision]\nUSD = 2\nJPY = 0",
+        )
+        .unwrap();
+        assert_eq!(
+            currencies_text(&config),
+            "CNY (default): not rounded\nJPY: 0 decimal places\nUSD: 2 decimal places"
+        );
+        let config: Beancount =
+            toml::from_str("root = \"\"\ndefault_currency = \"CNY\"\n[precision]\nCNY = 2")
+                .unwrap();
+        assert_eq!(currencies_text(&config), "CNY (default): 2 decimal places");
+        let config: Beancount = toml::from_str(
+            "root = \"\"\ndefault_currency = \"CNY\"\n[precision]\nUSD = 2\n\
+             [currency_symbols]\nCNY = \"¥\"\nUSD = \"$\"\nEUR = \"€\"",
+        )
+        .unwrap();
+        assert_eq!(
+            currencies_text(&config),
+            "CNY ¥ (default): not rounded\nEUR €: not rounded\nUSD $: 2 decimal places"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_accounts() {
+        let root = crate::utils::temp_dir("load_accounts");
+        let path = root.to_str().unwrap();
+        let accounts_file = root.join("accounts.bean");
+        std::fs::write(&accounts_file, "2021-01-01 open Assets:Cash\n").unwrap();
+        let state = RwLock::new(Database::default());
+        assert_eq!(load_accounts(&state, path).await.unwrap(), ["Assets:Cash"]);
+        assert!(state.read().await.accounts.fresh(path).unwrap().is_some());
+        // served from the cache
+        assert_eq!(load_accounts(&state, path).await.unwrap(), ["Assets:Cash"]);
+
+        std::fs::write(
+            &accounts_file,
+            "2021-01-01 open Assets:Cash\n2021-01-01 open Expenses:Food\n",
+        )
+        .unwrap();
+        assert_eq!(
+            refresh_accounts(&state, path).await.unwrap(),
+            ["Assets:Cash", "Expenses:Food"]
+        );
+        std::fs::remove_file(&accounts_file).unwrap();
+        assert!(load_accounts(&state, path).await.is_err());
+    }
+
+    #[test]
+    fn test_find_candidates() {
+        let mut accounts: Vec<_> = (1..=12)
+            .map(|i| format!("Expenses:Home:Rent{:02}", i))
+            .collect();
+        accounts.push(format!("Expenses:Home:{}", "Long".repeat(12)));
+        accounts.push("Assets:Cash".to_string());
+        let (count, candidates) = find_candidates(&accounts, "rent");
+        assert_eq!(count, 12);
+        assert_eq!(candidates.len(), FIND_LIMIT);
+        assert_eq!(
+            candidates[0],
+            (
+                "Expenses:Home:Rent01",
+                "find:Expenses:Home:Rent01".to_string()
+            )
+        );
+
+        let (count, candidates) = find_candidates(&accounts, "home long");
+        assert_eq!(count, 1);
+        assert!(candidates.is_empty());
+        assert_eq!(
+            find_candidates(&accounts, "CASH").1,
+            [("Assets:Cash", "find:Assets:Cash".to_string())]
+        );
+        assert_eq!(find_candidates(&accounts, "food").0, 0);
+    }
+
+    #[test]
+    fn test_auto_commits() {
+        let accounts: Vec<_> = ["Assets:Cash", "Expenses:Food", "Expenses:Food:Fruit"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let decide = |extra: &str, cmd: &str| {
+            let config: Beancount = toml::from_str(&format!(
+                "root = \"\"\ndefault_currency = \"CNY\"\n{}",
+                extra
+            ))
+            .unwrap();
+            let cmds = command_split(cmd).unwrap();
+            let txn = Transaction::today_from_command(
+                &cmds,
+                &accounts,
+                &config,
+                &ParseContext::new("CNY"),
+            )
+            .unwrap();
+            auto_commits(&config, &txn)
+        };
+        assert!(!decide("", "10 cash fruit"));
+        assert!(decide("auto_commit = true", "10 cash fruit"));
+        // "food" is picked by the last component among the food accounts
+        assert!(!decide("auto_commit = true", "10 cash food"));
+        assert!(!decide(
+            "auto_commit = true\nconfirm_above = 100",
+            "1000 cash fruit"
+        ));
+        assert!(decide(
+            "auto_commit = true\nconfirm_above = 100",
+            "100 cash fruit"
+        ));
+    }
+}
@@ -4,66 +4,743 @@ mod beancount;
 mod git;
 mod handler;
 
-use std::convert::TryInto;
-use std::fs::read_to_string;
-use std::path::PathBuf;
-use std::sync::Arc;
-
-use anyhow::Result;
-use log::{debug, error, info};
-use once_cell::sync::OnceCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::{TryFrom, TryInto};
+use std::fs::{read_to_string, File};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock as StdRwLock};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::{debug, error, info, warn};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 use tbot::contexts::methods::ChatMethods;
+use tbot::contexts::{Command, Text};
 use tbot::proxy::{Intercept, Proxy};
+use tbot::state::StatefulEventLoop;
 use tbot::types::callback::Origin;
+use tbot::types::chat::Id as ChatId;
+use tbot::types::message::Id as MessageId;
 use tbot::types::User;
 use tokio::sync::RwLock;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct Bot {
+    /// Overridden by the environment variable `TELEGRAM_BOT_TOKEN`
+    #[serde(default)]
     token: String,
+    /// Overridden by the environment variable `AUTH_SECRET`
+    #[serde(default)]
     secret: String,
     #[serde(default = "state_default")]
     state_file: String,
+    /// Users allowed to run admin commands. All authorized users are admins if empty.
+    #[serde(default)]
+    admins: Vec<i64>,
+    /// Seconds after which an unconfirmed transaction preview expires
+    confirm_timeout: Option<i64>,
+    /// Seconds after which a text message is too old to be handled, so that messages sent while
+    /// the bot is down are not replayed
+    #[serde(default = "message_max_age_default")]
+    message_max_age: i64,
+    /// Prefixes transactions entered in group chats have to start with, the first matched one is
+    /// stripped. Prefixes starting with `/` are also accepted as commands.
+    #[serde(default)]
+    group_prefixes: Vec<String>,
+    /// Maximum number of accounts listed in the message of `/accounts`, which lists all of them if
+    /// unset. `/accounts --file` is not limited.
+    accounts_limit: Option<usize>,
+    #[serde(default)]
+    startup_check: StartupCheck,
+    #[serde(default)]
+    empty_message: EmptyMessage,
+    #[serde(default)]
+    auth_scope: AuthScope,
+    /// Reply to unauthorized users sending transactions, telling them to `/auth`. They are
+    /// ignored silently if unset.
+    onboarding: Option<String>,
+    /// Seconds before the onboarding reply is sent again to the same user
+    #[serde(default = "onboarding_interval_default")]
+    onboarding_interval: i64,
+}
+
+/// What to do with messages without transactions, e.g. whitespace only
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyMessage {
+    /// Ignores them silently
+    #[default]
+    Ignore,
+    /// Replies the usage of transactions
+    Usage,
+}
+
+/// Where users authorized by `/auth` are authorized
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthScope {
+    /// In all chats
+    #[default]
+    Global,
+    /// Only in the chat they sent `/auth` in
+    Chat,
+}
+
+impl AuthScope {
+    /// Returns the key of the users authorized in `chat` in [`AuthUsers`]
+    fn key(self, chat: i64) -> i64 {
+        match self {
+            AuthScope::Global => GLOBAL_AUTH,
+            AuthScope::Chat => chat,
+        }
+    }
+}
+
+/// What to do when the ledger fails the check on startup
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupCheck {
+    /// Skips the check
+    Off,
+    /// Logs the failure and starts anyway
+    #[default]
+    Warn,
+    /// Refuses to start
+    Abort,
+}
+
+impl Bot {
+    /// Overrides the token and the secret with the environment variables got by `var`, and makes
+    /// sure they are set by either the config or the environment
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        for (field, name, value) in [
+            ("token", "TELEGRAM_BOT_TOKEN", &mut self.token),
+            ("secret", "AUTH_SECRET", &mut self.secret),
+        ] {
+            if let Some(env) = var(name) {
+                *value = env;
+            }
+            ensure!(
+                !value.is_empty(),
+                "bot.{} is neither in the config nor in the environment variable {}",
+                field,
+                name
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether a text message sent `age` seconds ago is handled
+    fn accepts_message(&self, age: i64) -> bool {
+        age <= self.message_max_age
+    }
+
+    /// Whether a preview sent `age` seconds ago can still be confirmed
+    fn accepts_confirmation(&self, age: i64) -> bool {
+        self.confirm_timeout.is_none_or(|timeout| age < timeout)
+    }
 }
 
 fn state_default() -> String {
     String::from("state.json")
 }
 
-#[derive(Debug, Deserialize)]
-struct Beancount {
+fn message_max_age_default() -> i64 {
+    180
+}
+
+fn onboarding_interval_default() -> i64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Beancount {
     root: String,
     default_currency: String,
+    #[serde(default = "max_length_default")]
+    max_narration_length: usize,
+    #[serde(default = "max_length_default")]
+    max_payee_length: usize,
+    #[serde(default)]
+    require_narration: bool,
+    #[serde(default = "max_postings_default")]
+    max_postings: usize,
+    /// Whether lines starting with `;` in a message of several transactions are ignored
+    #[serde(default)]
+    comment_lines: bool,
+    /// Whether `>` gives the narration and the rest of a command gives the payee
+    #[serde(default)]
+    swap_payee_narration: bool,
+    /// Whether to keep the spaces around payees and narrations instead of trimming them
+    #[serde(default)]
+    keep_surrounding_spaces: bool,
+    /// Whether to commit transactions with unambiguously matched accounts without confirmation
+    #[serde(default)]
+    auto_commit: bool,
+    /// Amount above which committing a transaction asks for another confirmation
+    confirm_above: Option<u64>,
+    /// Hour before which transactions are dated to the previous day
+    #[serde(default)]
+    day_cutoff_hour: u32,
+    /// Tag added by `/reimbursable`
+    #[serde(default = "reimbursable_tag_default")]
+    reimbursable_tag: String,
+    /// Whether to check again that the accounts of a transaction are still opened when it is
+    /// committed, as the ledger may have changed since its preview
+    #[serde(default)]
+    revalidate_accounts: bool,
+    /// Whether to check the ledger with `bean-check` before committing a transaction, which is
+    /// removed from its file if the check fails
+    #[serde(default)]
+    validate_before_commit: bool,
+    /// Command run by `validate_before_commit`
+    #[serde(default = "bean_check_default")]
+    bean_check: String,
+    /// File including the whole ledger, relative to the root
+    #[serde(default = "main_file_default")]
+    main_file: String,
+    /// Days after today within which transactions can be dated
+    max_future_days: Option<i64>,
+    /// Whether to record the user entering a transaction in its metadata
+    #[serde(default)]
+    entered_by: bool,
+    /// Whether to record the link to the message of a transaction in its metadata
+    #[serde(default)]
+    message_link: bool,
+    /// Git remote to pull from and push to, defaults to the upstream of the current branch
+    remote: Option<String>,
+    /// Git branch to pull from and push to, only used when `remote` is set
+    branch: Option<String>,
+    /// Minimum seconds between pulls of read-only commands, which pull every time if unset
+    pull_interval: Option<i64>,
+    /// Seconds within which consecutive transaction commits are squashed before pushing
+    squash_window: Option<i64>,
+    /// Whether commits are only squashed into commits to the same transaction file
+    #[serde(default)]
+    squash_same_file: bool,
+    /// Number of decimal places each currency is rounded to
+    #[serde(default)]
+    precision: HashMap<String, u32>,
+    /// Rates of foreign currencies in the default currency, used to show converted amounts in
+    /// previews only. Written as strings like `"7.2"` to be exact
+    #[serde(default)]
+    rates: HashMap<String, Decimal>,
+    /// Whether to show the totals of the positive postings per currency in previews
+    #[serde(default)]
+    preview_totals: bool,
+    #[serde(default)]
+    shortcuts: HashMap<String, Shortcut>,
+    /// Symbols shown before the numbers of amounts in previews instead of the currencies after
+    /// them. Committed transactions keep the currencies.
+    #[serde(default)]
+    currency_symbols: beancount::CurrencySymbols,
+    /// `strftime` format of the dates in previews, which has to include the year, the month and
+    /// the day. Committed transactions keep `YYYY-MM-DD`.
+    preview_date_format: Option<String>,
+    /// Accounts entered by numbers, which are no longer split amounts in account slots
+    #[serde(default)]
+    account_numbers: HashMap<String, String>,
+    /// Narrations of transactions without one, keyed by their expense accounts
+    #[serde(default)]
+    narrations: HashMap<String, String>,
+    /// Narration of transactions with a payee but without a narration, in which `{payee}` is
+    /// replaced by the payee. Such narrations are left empty if unset.
+    payee_narration: Option<String>,
+    /// Expense account used when a transaction is entered without one
+    fallback_expense_account: Option<String>,
+    /// Tags added to transactions using the accounts, keyed by the accounts or their parents
+    #[serde(default)]
+    account_tags: HashMap<String, String>,
+    /// Tags added to transactions whose narrations contain the keywords, ignoring case
+    #[serde(default)]
+    keyword_tags: HashMap<String, String>,
+    #[serde(default = "opening_balances_default")]
+    opening_balances_account: String,
+    #[serde(default)]
+    expense_sign: ExpenseSign,
+    #[serde(default)]
+    tag_spaces: TagSpaces,
+    #[serde(default)]
+    mark_default_currency: bool,
+    /// Whether to write complete transactions with the `txn` keyword instead of the `*` flag
+    #[serde(default)]
+    txn_keyword: bool,
+    /// Whether to pad the accounts of postings so that their amounts are aligned
+    #[serde(default)]
+    align_amounts: bool,
+    #[serde(default)]
+    posting_order: PostingOrder,
+    #[serde(default)]
+    rounding: Rounding,
+    #[serde(default = "account_separator_default")]
+    account_separator: String,
+    #[serde(default)]
+    granularity: Granularity,
+    /// Whether to insert transactions before the existing ones in their files, after the header,
+    /// so that the newest transactions are at the top
+    #[serde(default)]
+    prepend: bool,
+    /// Number of blank lines separating appended transactions
+    #[serde(default = "blank_lines_default")]
+    blank_lines: usize,
+    #[serde(default)]
+    match_mode: MatchMode,
+}
+
+/// Order of the rendered postings of a transaction
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PostingOrder {
+    /// The order they are entered in
+    #[default]
+    Input,
+    /// Debits before credits, each in the order they are entered in
+    Debits,
+    /// Alphabetical by account
+    Account,
+}
+
+/// Strategy of matching accounts by search terms
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Matches the full account names, falling back to the last components if ambiguous
+    #[default]
+    Smart,
+    /// Matches the full account names only
+    Strict,
+    /// Matches the beginnings of the account components only
+    Prefix,
+}
+
+/// Period of transactions placed in one file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// `txs/{year}/{month}.bean`
+    #[default]
+    Month,
+    /// `txs/{year}/{month}/{day}.bean`
+    Day,
+}
+
+/// Rounding strategy used when quantizing amounts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    /// Rounds half away from zero, e.g. `0.125` to `0.13`
+    #[default]
+    HalfUp,
+    /// Banker's rounding, rounds half to the nearest even digit, e.g. `0.125` to `0.12`
+    HalfEven,
+}
+
+/// Handling of whitespace in quoted tags like `'#trip japan'`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagSpaces {
+    #[default]
+    Reject,
+    /// Replaces whitespace with hyphens, e.g. `#trip-japan`
+    Hyphen,
+}
+
+/// Sign of the amount entered for an expense
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpenseSign {
+    /// The amount is what the expense account increases by, e.g. `10` for spending 10
+    #[default]
+    Positive,
+    /// The amount is what the spend account changes by, e.g. `-10` for spending 10
+    Negative,
 }
 
-#[derive(Debug, Deserialize)]
+fn max_postings_default() -> usize {
+    20
+}
+
+fn reimbursable_tag_default() -> String {
+    String::from("reimbursable")
+}
+
+fn blank_lines_default() -> usize {
+    1
+}
+
+fn bean_check_default() -> String {
+    String::from("bean-check")
+}
+
+fn main_file_default() -> String {
+    String::from("main.bean")
+}
+
+fn opening_balances_default() -> String {
+    String::from("Equity:Opening-Balances")
+}
+
+fn account_separator_default() -> String {
+    String::from(":")
+}
+
+/// Account pair a shortcut keyword expands to
+#[derive(Debug, Clone, Deserialize)]
+pub struct Shortcut {
+    account: String,
+    expense: String,
+}
+
+impl Beancount {
+    fn repo(&self) -> git::GitRepo<'_> {
+        git::GitRepo {
+            path: &self.root,
+            upstream: git::Upstream {
+                remote: self.remote.as_deref(),
+                branch: self.branch.as_deref(),
+            },
+            squash_window: self.squash_window,
+            squash_same_file: self.squash_same_file,
+        }
+    }
+}
+
+fn max_length_default() -> usize {
+    200
+}
+
+/// Settings of a specific chat
+#[derive(Debug, Clone, Deserialize)]
+struct Chat {
+    id: i64,
+    /// Payee of the transactions entered in the chat when the payee is omitted
+    payee: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 struct Config {
     bot: Bot,
     beancount: Beancount,
+    #[serde(default)]
+    chats: Vec<Chat>,
+}
+
+impl Config {
+    fn chat(&self, id: i64) -> Option<&Chat> {
+        self.chats.iter().find(|c| c.id == id)
+    }
+
+    /// Formats the config with the token and the secret redacted
+    fn sanitized(&self) -> String {
+        let mut config = self.clone();
+        config.bot.token = "<redacted>".to_string();
+        config.bot.secret = "<redacted>".to_string();
+        format!("{:#?}", config)
+    }
+}
+
+/// Key of the users authorized in all chats in [`AuthUsers`], which is not the id of any chat
+const GLOBAL_AUTH: i64 = 0;
+
+/// Authorized users keyed by the chats they are authorized in, or [`GLOBAL_AUTH`]
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "StoredAuthUsers")]
+pub struct AuthUsers(BTreeMap<i64, Vec<i64>>);
+
+/// Forms of [`AuthUsers`] in the state file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredAuthUsers {
+    /// Users authorized in all chats, saved before authorization was scoped
+    Flat(Vec<i64>),
+    /// Chat ids are strings as keys of JSON objects
+    Scoped(BTreeMap<String, Vec<i64>>),
+}
+
+impl TryFrom<StoredAuthUsers> for AuthUsers {
+    type Error = String;
+
+    fn try_from(stored: StoredAuthUsers) -> std::result::Result<Self, Self::Error> {
+        match stored {
+            StoredAuthUsers::Flat(users) if users.is_empty() => Ok(Self::default()),
+            StoredAuthUsers::Flat(users) => {
+                Ok(Self(std::iter::once((GLOBAL_AUTH, users)).collect()))
+            }
+            StoredAuthUsers::Scoped(users) => users
+                .into_iter()
+                .map(|(chat, users)| match chat.parse() {
+                    Ok(chat) => Ok((chat, users)),
+                    Err(_) => Err(format!("invalid chat id {}", chat)),
+                })
+                .collect::<std::result::Result<_, _>>()
+                .map(Self),
+        }
+    }
+}
+
+impl AuthUsers {
+    fn contains(&self, user: i64, key: i64) -> bool {
+        self.0.get(&key).is_some_and(|users| users.contains(&user))
+    }
+
+    fn insert(&mut self, user: i64, key: i64) {
+        let users = self.0.entry(key).or_default();
+        if !users.contains(&user) {
+            users.push(user);
+        }
+    }
+
+    /// Deauthorizes everyone, returning the number of distinct users deauthorized
+    fn clear(&mut self) -> usize {
+        let users: HashSet<_> = self.0.values().flatten().collect();
+        let count = users.len();
+        self.0.clear();
+        count
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.0.values().all(Vec::is_empty)
+    }
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Database {
     #[serde(default)]
-    auth_users: Vec<i64>,
+    auth_users: AuthUsers,
+    #[serde(default)]
+    review_queue: Vec<ReviewEntry>,
+    /// Default currencies of users set by `/currency`
+    #[serde(default)]
+    currencies: HashMap<i64, String>,
+    /// Statistics of the transactions committed by users, keyed by `YYYY-MM`
+    #[serde(default)]
+    stats: HashMap<i64, BTreeMap<String, MonthStats>>,
+    /// The last committed transaction, tagged by `/reimbursable`
+    #[serde(default)]
+    last_committed: Option<String>,
+    #[serde(skip)]
+    accounts: beancount::AccountCache,
+    #[serde(skip)]
+    previews: Vec<Preview>,
+    /// Transactions of the recent previews keyed by their chats and messages, committed instead of
+    /// the previews which may be localized
+    #[serde(skip)]
+    previewed: VecDeque<((ChatId, MessageId), String)>,
+    /// When the onboarding reply was last sent to unauthorized users
+    #[serde(skip)]
+    onboarded: HashMap<i64, i64>,
 }
 
-static CONFIG: OnceCell<Config> = OnceCell::new();
+/// Statistics of the transactions a user committed in a month
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MonthStats {
+    transactions: usize,
+    /// Amounts spent on expense accounts by currency, in decimal strings
+    spent: BTreeMap<String, String>,
+}
+
+/// Number of the recent previews whose transactions are kept in [`Database`]
+const MAX_PREVIEWED: usize = 256;
 
-fn get_config() -> &'static Config {
-    CONFIG.get().expect("Config hasn't been initialized")
+/// A transaction preview waiting for confirmation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    chat: ChatId,
+    message: MessageId,
+    text: String,
+    date: i64,
+}
+
+/// A transaction waiting to be reviewed before committing
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ReviewEntry {
+    txn: String,
+    cmd: Option<String>,
+}
+
+impl Database {
+    fn save(&self) -> Result<()> {
+        self.save_to(&get_config().bot.state_file)
+    }
+
+    fn save_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        serde_json::to_writer(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// Deauthorizes all the users, also forgetting their settings, statistics and transactions
+    /// if `all` is set. Returns the number of users deauthorized.
+    fn lockdown(&mut self, all: bool) -> usize {
+        if all {
+            self.currencies.clear();
+            self.stats.clear();
+            self.last_committed = None;
+            self.review_queue.clear();
+            self.previewed.clear();
+        }
+        self.auth_users.clear()
+    }
+
+    /// Removes the previews older than `timeout` seconds and returns them
+    fn take_expired_previews(&mut self, timeout: i64) -> Vec<Preview> {
+        let (expired, previews) = self
+            .previews
+            .drain(..)
+            .partition(|p| utils::elapsed(p.date) >= timeout);
+        self.previews = previews;
+        expired
+    }
+
+    /// Adds a transaction committed by `user` in `month` spending `spent` to their statistics
+    fn add_stats(&mut self, user: i64, month: &str, spent: &[(Decimal, &str)]) {
+        let stats = self
+            .stats
+            .entry(user)
+            .or_default()
+            .entry(month.to_string())
+            .or_default();
+        stats.transactions += 1;
+        for (number, currency) in spent {
+            let total = stats.spent.entry(currency.to_string()).or_default();
+            let sum = total.parse().unwrap_or(Decimal::ZERO) + number;
+            *total = sum.to_string();
+        }
+    }
+
+    /// Returns the statistics of `user` in `month`
+    fn month_stats(&self, user: i64, month: &str) -> Option<&MonthStats> {
+        self.stats.get(&user)?.get(month)
+    }
+
+    /// Whether to send the onboarding reply to the unauthorized `user` at `now`, which is not
+    /// done again within `interval` seconds. Users onboarded longer ago are forgotten.
+    fn should_onboard(&mut self, user: i64, now: i64, interval: i64) -> bool {
+        self.onboarded.retain(|_, last| now - *last < interval);
+        if self
+            .onboarded
+            .get(&user)
+            .is_some_and(|last| now - last < interval)
+        {
+            return false;
+        }
+        self.onboarded.insert(user, now);
+        true
+    }
+
+    /// Keeps `txn` as the transaction previewed in `message` of `chat`, forgetting the oldest
+    /// beyond [`MAX_PREVIEWED`]
+    fn add_previewed(&mut self, chat: ChatId, message: MessageId, txn: String) {
+        if self.previewed.len() >= MAX_PREVIEWED {
+            self.previewed.pop_front();
+        }
+        self.previewed.push_back(((chat, message), txn));
+    }
+
+    /// Returns the transaction previewed in `message` of `chat`
+    fn previewed(&self, chat: ChatId, message: MessageId) -> Option<&str> {
+        self.previewed
+            .iter()
+            .find(|(key, _)| *key == (chat, message))
+            .map(|(_, txn)| txn.as_str())
+    }
+
+    /// Removes the transaction `txn` from the review queue, returning its index and entry
+    fn take_review(&mut self, txn: &str) -> Option<(usize, ReviewEntry)> {
+        let idx = self.review_queue.iter().position(|e| e.txn == txn)?;
+        Some((idx, self.review_queue.remove(idx)))
+    }
+
+    /// Puts an entry taken by `take_review` back at its original index
+    fn restore_review(&mut self, idx: usize, entry: ReviewEntry) {
+        let idx = idx.min(self.review_queue.len());
+        self.review_queue.insert(idx, entry);
+    }
+
+    /// Returns the default currency of a user, which is `global` unless overridden by the user
+    fn default_currency<'a>(&'a self, user_id: Option<i64>, global: &'a str) -> &'a str {
+        user_id
+            .and_then(|id| self.currencies.get(&id))
+            .map_or(global, String::as_str)
+    }
+
+    /// Returns the settings overridden by `user`, one per line like `currency: USD`
+    fn overrides(&self, user: i64) -> Vec<String> {
+        let mut overrides = Vec::new();
+        if let Some(currency) = self.currencies.get(&user) {
+            overrides.push(format!("currency: {}", currency));
+        }
+        overrides
+    }
+
+    /// Resets the settings overridden by `user`, returning whether there were any
+    fn clear_overrides(&mut self, user: i64) -> bool {
+        self.currencies.remove(&user).is_some()
+    }
+
+    /// Whether `user` is authorized in `chat` under the configured scope
+    fn is_authorized(&self, user: i64, chat: i64) -> bool {
+        let key = get_config().bot.auth_scope.key(chat);
+        self.auth_users.contains(user, key)
+    }
+
+    fn is_admin(&self, user_id: i64, chat: i64) -> bool {
+        let admins = &get_config().bot.admins;
+        self.is_authorized(user_id, chat) && (admins.is_empty() || admins.contains(&user_id))
+    }
+}
+
+static CONFIG: StdRwLock<Option<Arc<Config>>> = StdRwLock::new(None);
+
+fn get_config() -> Arc<Config> {
+    let config = CONFIG.read().unwrap();
+    Arc::clone(config.as_ref().expect("Config hasn't been initialized"))
+}
+
+/// Replaces the active config. Handlers still holding the previous one keep it until they finish.
+fn set_config(config: Config) {
+    *CONFIG.write().unwrap() = Some(Arc::new(config));
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
-    let config: Config = toml::from_str(&read_to_string("bot.toml")?)?;
-    CONFIG.set(config).unwrap();
+    let path = config_path(std::env::args().skip(1), |name| std::env::var(name).ok())?;
+    let content = read_to_string(&path)
+        .with_context(|| format!("Failed to read config {}", path.display()))?;
+    let mut config: Config = toml::from_str(&content)?;
+    config.bot.apply_env(|name| std::env::var(name).ok())?;
+    set_config(config);
     run().await
 }
 
+/// Returns the path of the config file given by `--config <path>` in `args`, the environment
+/// variable `BOT_CONFIG` got by `var`, or `bot.toml` in the current directory, in this order
+fn config_path(
+    mut args: impl Iterator<Item = String>,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<PathBuf> {
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--config") {
+            Some("") => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--config requires a path"))?;
+                path = Some(value);
+            }
+            Some(value) if value.starts_with('=') => path = Some(value[1..].to_string()),
+            _ => bail!("Unknown argument {}", arg),
+        }
+    }
+    Ok(PathBuf::from(
+        path.or_else(|| var("BOT_CONFIG"))
+            .unwrap_or_else(|| "bot.toml".to_string()),
+    ))
+}
+
 fn init_proxy() -> Option<Proxy> {
     std::env::var("HTTPS_PROXY")
         .or_else(|_| std::env::var("https_proxy"))
@@ -76,19 +753,138 @@ fn init_proxy() -> Option<Proxy> {
         .ok()
 }
 
+/// Who can run a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Users authorized in the chat
+    User,
+    /// Admins authorized in the chat
+    Admin,
+}
+
+/// Registers command `name` handled by `handler` for the users with `access`, replying the errors
+/// of the handler
+fn command<H, F>(
+    bot: &mut StatefulEventLoop<RwLock<Database>>,
+    name: &'static str,
+    access: Access,
+    handler: H,
+) where
+    H: Fn(Arc<Command<Text>>, Arc<RwLock<Database>>) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    bot.command_if(
+        name,
+        move |context, state| async move {
+            let user = match context.from {
+                Some(User { id, .. }) => id.0,
+                None => return false,
+            };
+            let guard = state.read().await;
+            match access {
+                Access::User => guard.is_authorized(user, context.chat.id.0),
+                Access::Admin => guard.is_admin(user, context.chat.id.0),
+            }
+        },
+        move |context, state| {
+            let handled = handler(Arc::clone(&context), state);
+            async move {
+                if let Err(e) = handled.await {
+                    let r = context
+                        .send_message_in_reply(&format!("{:?}", e))
+                        .call()
+                        .await;
+                    if let Err(e) = r {
+                        error!("Send back error message failed: {:?}", e);
+                    } else {
+                        debug!("{:?}", e);
+                    }
+                }
+            }
+        },
+    );
+}
+
 async fn run() -> Result<()> {
+    let check = get_config().bot.startup_check;
+    if check != StartupCheck::Off {
+        let config = &get_config().beancount;
+        match handler::self_check(&config.repo(), &config.root) {
+            Ok(count) => info!("Ledger {} checked, {} accounts", config.root, count),
+            Err(e) if check == StartupCheck::Abort => {
+                return Err(e.context("Startup check failed"));
+            }
+            Err(e) => warn!("Startup check failed: {:?}", e),
+        }
+    }
     let state_file = &get_config().bot.state_file;
     let database: Database = if PathBuf::from(state_file).exists() {
         serde_json::from_str(&read_to_string(state_file)?)?
     } else {
         Default::default()
     };
-    let mut bot = if let Some(proxy) = init_proxy() {
+    let tgbot = if let Some(proxy) = init_proxy() {
         tbot::Bot::with_proxy(get_config().bot.token.clone(), proxy)
     } else {
         tbot::Bot::new(get_config().bot.token.clone())
+    };
+    let mut bot = tgbot.clone().stateful_event_loop(RwLock::new(database));
+
+    let commands: Vec<_> = get_config()
+        .bot
+        .group_prefixes
+        .iter()
+        .filter_map(|prefix| prefix.strip_prefix('/'))
+        // tbot takes the names as `&'static str`, they are registered once here
+        .map(|command| &*command.to_string().leak())
+        .collect();
+    if !commands.is_empty() {
+        // commands addressed as `/tx@username` are ignored unless the username is known
+        let me = tgbot.get_me().call().await?;
+        if let Some(username) = me.user.username {
+            bot.username(username.clone());
+            handler::BOT_USERNAME.set(username).ok();
+        }
+    }
+    for command in commands {
+        bot.command_if(
+            command,
+            |context, state| async move {
+                if let Some(User { id: user_id, .. }) = context.from {
+                    get_config()
+                        .bot
+                        .accepts_message(utils::elapsed(context.date))
+                        && state
+                            .read()
+                            .await
+                            .is_authorized(user_id.0, context.chat.id.0)
+                } else {
+                    false
+                }
+            },
+            |context, state| async move {
+                // the command is already trimmed off
+                if let Err(e) = handler::command(&context, &context.text.value, state).await {
+                    let r = context
+                        .send_message_in_reply(&format!("{:?}", e))
+                        .call()
+                        .await;
+                    if let Err(e) = r {
+                        error!("Send back error message failed: {:?}", e);
+                    } else {
+                        debug!("{:?}", e);
+                    }
+                }
+            },
+        );
+    }
+
+    if let Some(timeout) = get_config().bot.confirm_timeout {
+        tokio::spawn(handler::expire_previews(tgbot, bot.get_state(), timeout));
+    }
+    if let Some(window) = get_config().beancount.squash_window {
+        tokio::spawn(handler::push_deferred(window));
     }
-    .stateful_event_loop(RwLock::new(database));
 
     bot.command("auth", |context, state| async {
         if let Err(e) = handler::auth(context, state).await {
@@ -96,34 +892,61 @@ async fn run() -> Result<()> {
         }
     });
 
-    bot.command_if(
-        "accounts",
-        |context, state| async move {
-            if let Some(User { id: user_id, .. }) = context.from {
-                state.read().await.auth_users.contains(&user_id.0)
-            } else {
-                false
-            }
-        },
-        |context, state| async {
-            if let Err(e) = handler::accounts(context, state).await {
-                debug!("{:?}", e);
-            }
-        },
+    bot.command("whoami", |context, state| async {
+        if let Err(e) = handler::whoami(context, state).await {
+            error!("{:?}", e);
+        }
+    });
+
+    command(&mut bot, "accounts", Access::User, handler::accounts);
+    command(&mut bot, "find", Access::User, handler::find);
+    command(&mut bot, "refresh", Access::Admin, handler::refresh);
+    command(&mut bot, "review", Access::User, handler::review);
+    command(&mut bot, "pending", Access::User, handler::pending);
+    command(&mut bot, "push", Access::User, handler::push);
+    command(
+        &mut bot,
+        "reimbursable",
+        Access::User,
+        handler::reimbursable,
     );
+    command(&mut bot, "retry", Access::User, handler::retry);
+    command(&mut bot, "lastcommit", Access::User, handler::lastcommit);
+    command(&mut bot, "calc", Access::User, handler::calc);
+    command(&mut bot, "tokens", Access::User, handler::tokens);
+    command(&mut bot, "shortcuts", Access::User, handler::shortcuts);
+    command(&mut bot, "opening", Access::User, handler::opening);
+    command(&mut bot, "currency", Access::User, handler::currency);
+    command(&mut bot, "mystats", Access::User, handler::mystats);
+    command(&mut bot, "session", Access::User, handler::session);
+    command(&mut bot, "spent", Access::User, handler::spent);
+    command(&mut bot, "setroot", Access::Admin, handler::setroot);
+    command(&mut bot, "config", Access::Admin, handler::config);
+    command(&mut bot, "lockdown", Access::Admin, handler::lockdown);
+    command(&mut bot, "close", Access::Admin, handler::close);
+    command(&mut bot, "diff", Access::User, handler::diff);
+    command(&mut bot, "where", Access::User, handler::target);
+    command(&mut bot, "currencies", Access::User, handler::currencies);
+    command(&mut bot, "export", Access::User, handler::export);
 
     bot.text_if(
         |context, state| async move {
             if let Some(User { id: user_id, .. }) = context.from {
-                // ignore messages that are 3 minutes or older
-                utils::elapsed(context.date) <= 180
-                    && state.read().await.auth_users.contains(&user_id.0)
+                get_config()
+                    .bot
+                    .accepts_message(utils::elapsed(context.date))
+                    && handler::transaction_text(&context).is_some()
+                    && state
+                        .read()
+                        .await
+                        .is_authorized(user_id.0, context.chat.id.0)
             } else {
                 false
             }
         },
         |context, state| async move {
-            if let Err(e) = handler::command(Arc::clone(&context), state).await {
+            let text = handler::transaction_text(&context).unwrap_or_default();
+            if let Err(e) = handler::command(&context, text, state).await {
                 let r = context
                     .send_message_in_reply(&format!("{:?}", e))
                     .call()
@@ -137,10 +960,47 @@ async fn run() -> Result<()> {
         },
     );
 
+    bot.text_if(
+        |context, state| async move {
+            if let Some(User { id: user_id, .. }) = context.from {
+                let bot = &get_config().bot;
+                bot.onboarding.is_some()
+                    && bot.accepts_message(utils::elapsed(context.date))
+                    && handler::transaction_text(&context).is_some()
+                    && !state
+                        .read()
+                        .await
+                        .is_authorized(user_id.0, context.chat.id.0)
+            } else {
+                false
+            }
+        },
+        |context, state| async move {
+            let bot = &get_config().bot;
+            let user = context.from.as_ref().map_or(0, |u| u.id.0);
+            let reply =
+                state
+                    .write()
+                    .await
+                    .should_onboard(user, context.date, bot.onboarding_interval);
+            let msg = bot.onboarding.as_deref().unwrap_or_default();
+            if reply {
+                if let Err(e) = context.send_message_in_reply(msg).call().await {
+                    error!("Send onboarding message failed: {:?}", e);
+                }
+            }
+        },
+    );
+
     bot.data_callback_if(
         |context, state| async move {
             let user_id = context.from.id.0;
-            state.read().await.auth_users.contains(&user_id)
+            match context.origin {
+                Origin::Message(ref msg) => {
+                    state.read().await.is_authorized(user_id, msg.chat.id.0)
+                }
+                _ => false,
+            }
         },
         |context, state| async move {
             if let Err(e) = handler::confirm(Arc::clone(&context), state).await {
@@ -164,3 +1024,305 @@ async fn run() -> Result<()> {
     bot.polling().start().await.expect("Bot start failed");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_review_queue() {
+        let entry = ReviewEntry {
+            txn: "2021-03-05 ! \"lunch\"\n    Expenses:Food 10 CNY\n    Assets:Cash -10 CNY".into(),
+            cmd: Some("10 cash food lunch".into()),
+        };
+        let mut db = Database::default();
+        db.review_queue.push(entry.clone());
+        let json = serde_json::to_string(&db).unwrap();
+        let mut db: Database = serde_json::from_str(&json).unwrap();
+        assert_eq!(db.review_queue.len(), 1);
+        assert_eq!(db.review_queue[0], entry);
+
+        assert_eq!(db.take_review("2021-03-05 ! \"dinner\""), None);
+        assert_eq!(db.take_review(&entry.txn), Some((0, entry.clone())));
+        assert!(db.review_queue.is_empty());
+        assert_eq!(db.take_review(&entry.txn), None);
+
+        let other = ReviewEntry {
+            txn: "2021-03-05 ! \"dinner\"".into(),
+            cmd: None,
+        };
+        db.review_queue.push(entry.clone());
+        db.review_queue.push(other.clone());
+        let (idx, taken) = db.take_review(&entry.txn).unwrap();
+        assert_eq!(idx, 0);
+        db.restore_review(idx, taken);
+        assert_eq!(db.review_queue, vec![entry, other]);
+    }
+
+    #[test]
+    fn test_sanitized_config() {
+        let config: Config = toml::from_str(
+            r#"[bot]
+            token = "123456:ABC-DEF"
+            secret = "p@ssw0rd"
+
+            [beancount]
+            root = "/path/to/beancount"
+            default_currency = "CNY""#,
+        )
+        .unwrap();
+        let text = config.sanitized();
+        assert!(!text.contains("123456:ABC-DEF"));
+        assert!(!text.contains("p@ssw0rd"));
+        assert!(text.contains("token: \"<redacted>\""));
+        assert!(text.contains("secret: \"<redacted>\""));
+        assert!(text.contains("root: \"/path/to/beancount\""));
+        assert_eq!(config.bot.token, "123456:ABC-DEF");
+    }
+
+    #[test]
+    fn test_should_onboard() {
+        let mut db = Database::default();
+        assert!(db.should_onboard(1, 1000, 3600));
+        assert!(!db.should_onboard(1, 1000, 3600));
+        assert!(!db.should_onboard(1, 4599, 3600));
+        assert!(db.should_onboard(2, 1001, 3600));
+        assert!(db.should_onboard(1, 4600, 3600));
+        assert!(!db.should_onboard(1, 5000, 3600));
+        // user 2 is forgotten once the interval has passed
+        assert_eq!(db.onboarded.len(), 1);
+        assert!(db.should_onboard(3, 8200, 3600));
+        assert_eq!(db.onboarded.len(), 1);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut db = Database::default();
+        db.add_stats(1, "2021-03", &[(Decimal::new(105, 1), "CNY")]);
+        db.add_stats(
+            1,
+            "2021-03",
+            &[(Decimal::new(20, 0), "CNY"), (Decimal::new(5, 0), "USD")],
+        );
+        db.add_stats(1, "2021-04", &[]);
+        db.add_stats(2, "2021-03", &[(Decimal::new(-3, 0), "CNY")]);
+        let json = serde_json::to_string(&db).unwrap();
+        let db: Database = serde_json::from_str(&json).unwrap();
+
+        let march = db.month_stats(1, "2021-03").unwrap();
+        assert_eq!(march.transactions, 2);
+        assert_eq!(march.spent["CNY"], "30.5");
+        assert_eq!(march.spent["USD"], "5");
+        // months are counted separately
+        let april = db.month_stats(1, "2021-04").unwrap();
+        assert_eq!(april.transactions, 1);
+        assert!(april.spent.is_empty());
+        assert_eq!(db.month_stats(1, "2021-05"), None);
+        // and so are users
+        assert_eq!(db.month_stats(2, "2021-03").unwrap().spent["CNY"], "-3");
+        assert_eq!(db.month_stats(3, "2021-03"), None);
+    }
+
+    #[test]
+    fn test_lockdown() {
+        let path = utils::temp_dir("lockdown").join("state.json");
+        let mut db = Database::default();
+        db.auth_users.insert(1, GLOBAL_AUTH);
+        db.auth_users.insert(2, GLOBAL_AUTH);
+        db.auth_users.insert(2, -100);
+        db.currencies.insert(1, "USD".to_string());
+        db.add_stats(1, "2021-03", &[]);
+        db.last_committed = Some("2021-03-05 * \"lunch\"".to_string());
+        db.review_queue.push(ReviewEntry {
+            txn: "2021-03-05 ! \"dinner\"".to_string(),
+            cmd: None,
+        });
+        assert_eq!(db.lockdown(false), 2);
+        db.save_to(&path).unwrap();
+        let mut db: Database = serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        assert!(db.auth_users.is_empty());
+        assert_eq!(db.default_currency(Some(1), "CNY"), "USD");
+        assert!(db.month_stats(1, "2021-03").is_some());
+        assert!(db.last_committed.is_some());
+        assert_eq!(db.review_queue.len(), 1);
+
+        db.auth_users.insert(3, GLOBAL_AUTH);
+        assert_eq!(db.lockdown(true), 1);
+        db.save_to(&path).unwrap();
+        let db: Database = serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        assert!(db.auth_users.is_empty());
+        assert!(db.currencies.is_empty());
+        assert!(db.stats.is_empty());
+        assert!(db.last_committed.is_none());
+        assert!(db.review_queue.is_empty());
+    }
+
+    #[test]
+    fn test_auth_scope() {
+        let mut users = AuthUsers::default();
+        users.insert(1, AuthScope::Chat.key(-100));
+        users.insert(2, AuthScope::Global.key(-100));
+        users.insert(1, AuthScope::Chat.key(-100));
+        assert!(users.contains(1, AuthScope::Chat.key(-100)));
+        assert!(!users.contains(1, AuthScope::Chat.key(-200)));
+        assert!(!users.contains(1, AuthScope::Global.key(-100)));
+        assert!(users.contains(2, AuthScope::Global.key(-200)));
+        assert!(!users.contains(2, AuthScope::Chat.key(-200)));
+
+        let json = serde_json::to_string(&users).unwrap();
+        assert_eq!(json, r#"{"-100":[1],"0":[2]}"#);
+        assert_eq!(serde_json::from_str::<AuthUsers>(&json).unwrap(), users);
+        assert!(serde_json::from_str::<AuthUsers>(r#"{"chat":[1]}"#).is_err());
+        assert_eq!(users.clear(), 2);
+        assert!(users.is_empty());
+
+        // flat lists saved before are users authorized in all chats
+        let db: Database = serde_json::from_str(r#"{"auth_users":[1,2]}"#).unwrap();
+        assert!(db.auth_users.contains(2, AuthScope::Global.key(-100)));
+        assert!(!db.auth_users.contains(2, AuthScope::Chat.key(-100)));
+        let db: Database = serde_json::from_str(r#"{"auth_users":[]}"#).unwrap();
+        assert!(db.auth_users.is_empty());
+    }
+
+    #[test]
+    fn test_default_currency() {
+        let mut db = Database::default();
+        db.currencies.insert(1, "USD".to_string());
+        assert_eq!(db.default_currency(Some(1), "CNY"), "USD");
+        assert_eq!(db.default_currency(Some(2), "CNY"), "CNY");
+        assert_eq!(db.default_currency(None, "CNY"), "CNY");
+        let json = serde_json::to_string(&db).unwrap();
+        let db: Database = serde_json::from_str(&json).unwrap();
+        assert_eq!(db.default_currency(Some(1), "CNY"), "USD");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut db = Database::default();
+        db.currencies.insert(1, "USD".to_string());
+        db.currencies.insert(2, "JPY".to_string());
+        assert_eq!(db.overrides(1), ["currency: USD"]);
+        assert!(db.overrides(3).is_empty());
+        assert!(db.clear_overrides(1));
+        assert!(!db.clear_overrides(1));
+        assert!(db.overrides(1).is_empty());
+        assert_eq!(db.default_currency(Some(1), "CNY"), "CNY");
+        // other users keep theirs
+        assert_eq!(db.overrides(2), ["currency: JPY"]);
+    }
+
+    #[test]
+    fn test_message_ages() {
+        let bot = |toml: &str| toml::from_str::<Bot>(toml).unwrap();
+        let config = bot("");
+        assert!(config.accepts_message(180));
+        assert!(!config.accepts_message(181));
+        assert!(config.accepts_confirmation(86400));
+
+        let config = bot("message_max_age = 60\nconfirm_timeout = 3600");
+        assert!(config.accepts_message(60));
+        assert!(!config.accepts_message(61));
+        // previews outlive the freshness of text messages
+        assert!(config.accepts_confirmation(600));
+        assert!(!config.accepts_confirmation(3600));
+    }
+
+    #[test]
+    fn test_config_path() {
+        let path = |args: &[&str], env: Option<&'static str>| {
+            let args = args.iter().map(ToString::to_string);
+            config_path(args, |name| match name {
+                "BOT_CONFIG" => env.map(ToString::to_string),
+                _ => None,
+            })
+            .map(|p| p.to_str().unwrap().to_string())
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(path(&[], None).unwrap(), "bot.toml");
+        assert_eq!(path(&[], Some("/etc/env.toml")).unwrap(), "/etc/env.toml");
+        assert_eq!(
+            path(&["--config", "/etc/cli.toml"], Some("/etc/env.toml")).unwrap(),
+            "/etc/cli.toml"
+        );
+        assert_eq!(
+            path(&["--config=/etc/cli.toml"], None).unwrap(),
+            "/etc/cli.toml"
+        );
+        assert_eq!(
+            path(&["--config"], None).unwrap_err(),
+            "--config requires a path"
+        );
+        assert_eq!(
+            path(&["--verbose"], None).unwrap_err(),
+            "Unknown argument --verbose"
+        );
+    }
+
+    #[test]
+    fn test_env_override() {
+        let bot = |toml: &str| toml::from_str::<Bot>(toml).unwrap();
+        fn env(token: Option<&'static str>) -> impl Fn(&str) -> Option<String> {
+            move |name| match name {
+                "TELEGRAM_BOT_TOKEN" => token.map(ToString::to_string),
+                "AUTH_SECRET" => Some("env-secret".to_string()),
+                _ => None,
+            }
+        }
+
+        let mut config = bot(r#"token = "file-token""#);
+        config.apply_env(env(None)).unwrap();
+        assert_eq!(config.token, "file-token");
+        assert_eq!(config.secret, "env-secret");
+
+        let mut config = bot(r#"token = "file-token"
+                                secret = "file-secret""#);
+        config.apply_env(env(Some("env-token"))).unwrap();
+        assert_eq!(config.token, "env-token");
+        assert_eq!(config.secret, "env-secret");
+
+        let mut config = bot("");
+        let err = config.apply_env(env(None)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bot.token is neither in the config nor in the environment variable TELEGRAM_BOT_TOKEN"
+        );
+    }
+
+    #[test]
+    fn test_expired_previews() {
+        let now = chrono::Utc::now().timestamp();
+        let preview = |message, date| Preview {
+            chat: tbot::types::chat::Id(1),
+            message: tbot::types::message::Id(message),
+            text: String::new(),
+            date,
+        };
+        let mut db = Database {
+            previews: vec![
+                preview(1, now - 600),
+                preview(2, now - 300),
+                preview(3, now - 10),
+                preview(4, now),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            db.take_expired_previews(300),
+            [preview(1, now - 600), preview(2, now - 300)]
+        );
+        assert_eq!(db.previews, [preview(3, now - 10), preview(4, now)]);
+        assert!(db.take_expired_previews(300).is_empty());
+    }
+
+    #[test]
+    fn test_previewed() {
+        let mut db = Database::default();
+        for message in 0..=MAX_PREVIEWED as u32 {
+            db.add_previewed(ChatId(1), MessageId(message), message.to_string());
+        }
+        assert_eq!(db.previewed.len(), MAX_PREVIEWED);
+        assert_eq!(db.previewed(ChatId(1), MessageId(0)), None);
+        assert_eq!(db.previewed(ChatId(1), MessageId(1)), Some("1"));
+        assert_eq!(db.previewed(ChatId(2), MessageId(1)), None);
+    }
+}
@@ -1,33 +1,57 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use std::fmt;
 
 // got the idea from `shlex` crate
 mod shlex {
-    use anyhow::{bail, Result};
+    use super::ParseError;
     use std::iter::Peekable;
+    type Result<T> = std::result::Result<T, ParseError>;
+
     pub(super) struct Shlex<'a> {
         in_iter: Peekable<core::str::Chars<'a>>,
+        /// number of characters consumed
+        pos: usize,
     }
 
     impl<'a> Shlex<'a> {
         pub(super) fn new(in_str: &'a str) -> Self {
             Shlex {
                 in_iter: in_str.chars().peekable(),
+                pos: 0,
+            }
+        }
+
+        fn next_char(&mut self) -> Option<char> {
+            let ch = self.in_iter.next()?;
+            self.pos += 1;
+            Some(ch)
+        }
+
+        /// Returns an error located at the last consumed character
+        fn error(&self, msg: &'static str) -> ParseError {
+            ParseError {
+                msg,
+                offset: self.pos - 1,
             }
         }
 
         fn parse_word(&mut self) -> Result<Option<String>> {
             // skip initial whitespace
-            while self.in_iter.next_if(|x| matches!(x, ' ' | '\t')).is_some() {}
+            while self.in_iter.next_if(|x| matches!(x, ' ' | '\t')).is_some() {
+                self.pos += 1;
+            }
             if self.in_iter.peek().is_none() {
                 // nothing left to parse
                 return Ok(None);
             }
             let mut result = String::new();
-            while let Some(ch) = self.in_iter.next() {
+            while let Some(ch) = self.next_char() {
                 match ch {
                     '"' => self.parse_double(&mut result)?,
                     '\'' => self.parse_single(&mut result)?,
-                    '\n' => bail!("newline within argument"),
+                    '\n' => return Err(self.error("newline within argument")),
                     ' ' | '\t' => break,
                     _ => result.push(ch),
                 }
@@ -36,15 +60,16 @@ mod shlex {
         }
 
         fn parse_double(&mut self, result: &mut String) -> Result<()> {
-            while let Some(ch) = self.in_iter.next() {
+            let start = self.error("unmatched double quote");
+            while let Some(ch) = self.next_char() {
                 match ch {
                     '"' => return Ok(()),
-                    '\n' => bail!("newline within double quote"),
+                    '\n' => return Err(self.error("newline within double quote")),
                     '\\' => {
-                        if let Some(ch2) = self.in_iter.next() {
+                        if let Some(ch2) = self.next_char() {
                             match ch2 {
                                 '"' | '\\' => result.push(ch2),
-                                '\n' => bail!("newline within double quote"),
+                                '\n' => return Err(self.error("newline within double quote")),
                                 _ => {
                                     result.push('\\');
                                     result.push(ch2);
@@ -55,19 +80,19 @@ mod shlex {
                     _ => result.push(ch),
                 }
             }
-            bail!("unmatched double quote");
+            Err(start)
         }
 
         fn parse_single(&mut self, result: &mut String) -> Result<()> {
-            #[allow(clippy::while_let_on_iterator)]
-            while let Some(ch) = self.in_iter.next() {
+            let start = self.error("unmatched single quote");
+            while let Some(ch) = self.next_char() {
                 match ch {
                     '\'' => return Ok(()),
-                    '\n' => bail!("newline within single quote"),
+                    '\n' => return Err(self.error("newline within single quote")),
                     _ => result.push(ch),
                 }
             }
-            bail!("unmatched single quote");
+            Err(start)
         }
     }
 
@@ -79,13 +104,174 @@ mod shlex {
     }
 }
 
+/// Error of [`command_split`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub msg: &'static str,
+    /// Offset of the error in characters
+    pub offset: usize,
+}
+
+impl ParseError {
+    /// Returns the line of `input` where the error occurs, followed by a caret pointing at the
+    /// error position
+    pub fn annotate(&self, input: &str) -> String {
+        let mut line_start = 0;
+        for (i, ch) in input.chars().enumerate().take(self.offset) {
+            if ch == '\n' {
+                line_start = i + 1;
+            }
+        }
+        let line: String = input
+            .chars()
+            .skip(line_start)
+            .take_while(|&ch| ch != '\n')
+            .collect();
+        // keep the tabs so that the caret is aligned
+        let padding: String = line
+            .chars()
+            .take(self.offset - line_start)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!("{}\n{}^", line, padding)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 /// Splits a command `s` into a list of arguments in a syntax similar to shell's:
 /// - arguments are whitespace-separated
 /// - arguments containing spaces can be quoted in double or single quotes
 /// - double quotes within double quotes can be escaped by `\"`
 /// - no escape is allowed in single quotes
-pub fn command_split(s: &str) -> Result<Vec<String>> {
-    shlex::Shlex::new(s).collect::<Result<_>>()
+pub fn command_split(s: &str) -> std::result::Result<Vec<String>, ParseError> {
+    shlex::Shlex::new(s).collect()
+}
+
+/// Splits a message into commands, one per line, skipping blank lines and, if `comments` is set,
+/// lines starting with `;`. Each command is returned with its 1-based line number.
+pub fn command_split_lines(s: &str, comments: bool) -> Result<Vec<(usize, Vec<String>)>> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !(line.is_empty() || comments && line.starts_with(';'))
+        })
+        .map(|(i, line)| {
+            let cmd = command_split(line).map_err(|e| {
+                anyhow!(
+                    "Invalid command on line {}: {}\n{}",
+                    i + 1,
+                    e,
+                    e.annotate(line)
+                )
+            })?;
+            Ok((i + 1, cmd))
+        })
+        .collect()
+}
+
+/// Deepest nesting of parentheses and signs [`Calc`] evaluates, which bounds its recursion
+const CALC_MAX_DEPTH: usize = 64;
+
+/// Evaluator of arithmetic expressions of numbers, `+ - * /` and parentheses
+struct Calc<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// Nesting depth of the factor being evaluated
+    depth: usize,
+}
+
+impl<'a> Calc<'a> {
+    /// Consumes the next non-whitespace character if it is one of `chs`
+    fn next_if_any(&mut self, chs: &[char]) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.next_if(|c| chs.contains(c))
+    }
+
+    fn expr(&mut self) -> Result<Decimal> {
+        let mut value = self.term()?;
+        while let Some(op) = self.next_if_any(&['+', '-']) {
+            let rhs = self.term()?;
+            value = match op {
+                '+' => value.checked_add(rhs),
+                _ => value.checked_sub(rhs),
+            }
+            .ok_or_else(|| anyhow!("Overflow"))?;
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<Decimal> {
+        let mut value = self.factor()?;
+        while let Some(op) = self.next_if_any(&['*', '/']) {
+            let rhs = self.factor()?;
+            value = match op {
+                '*' => value.checked_mul(rhs).ok_or_else(|| anyhow!("Overflow"))?,
+                _ => value
+                    .checked_div(rhs)
+                    .ok_or_else(|| anyhow!("Division by zero"))?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> Result<Decimal> {
+        self.depth += 1;
+        let value = self.nested_factor();
+        self.depth -= 1;
+        value
+    }
+
+    fn nested_factor(&mut self) -> Result<Decimal> {
+        if self.depth > CALC_MAX_DEPTH {
+            return Err(anyhow!("Expression nested too deeply"));
+        }
+        if let Some(sign) = self.next_if_any(&['+', '-']) {
+            let value = self.factor()?;
+            return Ok(if sign == '-' { -value } else { value });
+        }
+        if self.next_if_any(&['(']).is_some() {
+            let value = self.expr()?;
+            self.next_if_any(&[')'])
+                .ok_or_else(|| anyhow!("Unmatched parenthesis"))?;
+            return Ok(value);
+        }
+        let mut number = String::new();
+        while let Some(ch) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+            number.push(ch);
+        }
+        if number.is_empty() {
+            return Err(match self.chars.peek() {
+                Some(ch) => anyhow!("Unexpected character '{}'", ch),
+                None => anyhow!("Unexpected end of expression"),
+            });
+        }
+        number
+            .parse()
+            .map_err(|_| anyhow!("Invalid number {}", number))
+    }
+}
+
+/// Evaluates an arithmetic expression of numbers, `+ - * /` and parentheses. The result is rounded
+/// to 8 decimal places.
+pub fn evaluate(s: &str) -> Result<Decimal> {
+    let mut calc = Calc {
+        chars: s.chars().peekable(),
+        depth: 0,
+    };
+    let value = calc.expr()?;
+    if let Some(ch) = calc.chars.find(|c| !c.is_whitespace()) {
+        return Err(anyhow!("Unexpected character '{}'", ch));
+    }
+    Ok(value
+        .round_dp_with_strategy(8, RoundingStrategy::MidpointAwayFromZero)
+        .normalize())
 }
 
 pub fn escape_string(s: &str) -> String {
@@ -96,15 +282,75 @@ pub fn naive_today() -> chrono::NaiveDate {
     chrono::offset::Local::today().naive_local()
 }
 
+/// Returns the date of transactions entered at `hour` of `today`, which is the previous day if
+/// `hour` is before `cutoff_hour`
+pub fn ledger_date(today: chrono::NaiveDate, hour: u32, cutoff_hour: u32) -> chrono::NaiveDate {
+    if hour < cutoff_hour {
+        today.pred()
+    } else {
+        today
+    }
+}
+
+/// Returns the date of transactions entered now, see [`ledger_date`]
+pub fn ledger_today(cutoff_hour: u32) -> chrono::NaiveDate {
+    use chrono::Timelike;
+    ledger_date(naive_today(), chrono::Local::now().hour(), cutoff_hour)
+}
+
+/// Parses a month in the form of `YYYY-MM` into its first day. The month of `today` is used if `s`
+/// is empty.
+pub fn parse_month(s: &str, today: chrono::NaiveDate) -> Result<chrono::NaiveDate> {
+    use chrono::Datelike;
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(today.with_day(1).unwrap());
+    }
+    chrono::NaiveDate::parse_from_str(&format!("{}-01", s), "%F")
+        .map_err(|_| anyhow!("Invalid month '{}', expected YYYY-MM", s))
+}
+
+/// Parses a date in the form of `YYYY-MM-DD`, or `MM-DD` in the year of `today`
+pub fn parse_date(s: &str, today: chrono::NaiveDate) -> Option<chrono::NaiveDate> {
+    use chrono::Datelike;
+    if !s.chars().all(|c| c.is_ascii_digit() || c == '-') {
+        return None;
+    }
+    let s = match s.matches('-').count() {
+        1 => format!("{}-{}", today.year(), s),
+        2 => s.to_string(),
+        _ => return None,
+    };
+    chrono::NaiveDate::parse_from_str(&s, "%F").ok()
+}
+
 pub fn elapsed(time: i64) -> i64 {
     let now = chrono::Utc::now().naive_utc();
     let from = chrono::NaiveDateTime::from_timestamp(time, 0);
     (now - from).num_seconds()
 }
 
-/// Returns the last component of a colon-separated account string
-pub fn last_component(s: &str) -> &str {
-    s.rsplit_once(':').map(|x| x.1).unwrap_or(s)
+/// Returns the last component of an account string separated by `sep`
+pub fn last_component<'a>(s: &'a str, sep: &str) -> &'a str {
+    s.rsplit_once(sep).map(|x| x.1).unwrap_or(s)
+}
+
+/// Determines whether the account string `s` separated by `sep` is under the root account `root`
+pub fn has_root(s: &str, root: &str, sep: &str) -> bool {
+    s.split(sep).next() == Some(root)
+}
+
+/// Creates an empty directory under the system temporary directory for tests
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("beancount-bot-{}", std::process::id()))
+        .join(name);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
 // taken from once_cell documentation
@@ -117,7 +363,10 @@ macro_rules! regex {
 
 #[cfg(test)]
 mod tests {
-    use super::command_split;
+    use super::{
+        command_split, command_split_lines, evaluate, has_root, last_component, ledger_date,
+        parse_date, parse_month, CALC_MAX_DEPTH,
+    };
 
     fn verify(input: &str, result: &[&str]) {
         assert_eq!(
@@ -168,4 +417,184 @@ mod tests {
             &[">公司", "10 CNY", "ali", "food \"out", "narr the rest"],
         );
     }
+
+    #[test]
+    fn test_split_lines() {
+        let cmds = command_split_lines(
+            "10 ali food lunch\n\n  \n20 'ali pay' food 'dinner out'\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            cmds,
+            vec![
+                (1, vec!["10", "ali", "food", "lunch"]),
+                (4, vec!["20", "ali pay", "food", "dinner out"]),
+            ]
+            .into_iter()
+            .map(|(n, cmd)| (n, cmd.into_iter().map(ToString::to_string).collect()))
+            .collect::<Vec<(usize, Vec<String>)>>()
+        );
+
+        let err = command_split_lines("10 ali food lunch\n20 ali food 'dinner", false).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "Invalid command on line 2: unmatched single quote\n20 ali food 'dinner\n            ^"
+        );
+
+        let text = "; lunch\n10 ali food #work\n  ; dinner 'out\n20 ali food";
+        let cmds = command_split_lines(text, true).unwrap();
+        assert_eq!(
+            cmds,
+            [
+                (
+                    2,
+                    vec![
+                        "10".to_string(),
+                        "ali".into(),
+                        "food".into(),
+                        "#work".into()
+                    ]
+                ),
+                (4, vec!["20".to_string(), "ali".into(), "food".into()]),
+            ]
+        );
+        let err = command_split_lines(text, false).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid command on line 3"));
+    }
+
+    #[test]
+    fn test_parse_month() {
+        use crate::beancount::transaction_file;
+        use chrono::NaiveDate;
+        let today = NaiveDate::from_ymd(2021, 3, 5);
+        let path = |s: &str| {
+            let month = parse_month(s, today).unwrap();
+            transaction_file("/ledger", month, crate::Granularity::Month)
+        };
+        assert_eq!(path(""), std::path::Path::new("/ledger/txs/2021/03.bean"));
+        assert_eq!(
+            path(" 2024-01 "),
+            std::path::Path::new("/ledger/txs/2024/01.bean")
+        );
+        assert_eq!(
+            parse_month("2024-13", today).unwrap_err().to_string(),
+            "Invalid month '2024-13', expected YYYY-MM"
+        );
+        assert!(parse_month("2024-01-05", today).is_err());
+        assert!(parse_month("jan", today).is_err());
+    }
+
+    #[test]
+    fn test_ledger_date() {
+        use chrono::NaiveDate;
+        let today = NaiveDate::from_ymd(2021, 3, 1);
+        assert_eq!(ledger_date(today, 0, 0), today);
+        assert_eq!(ledger_date(today, 3, 4), NaiveDate::from_ymd(2021, 2, 28));
+        assert_eq!(ledger_date(today, 4, 4), today);
+        assert_eq!(ledger_date(today, 23, 4), today);
+        assert_eq!(
+            ledger_date(NaiveDate::from_ymd(2021, 1, 1), 0, 4),
+            NaiveDate::from_ymd(2020, 12, 31)
+        );
+    }
+
+    #[test]
+    fn test_parse_date() {
+        use chrono::NaiveDate;
+        let today = NaiveDate::from_ymd(2021, 3, 5);
+        let date = |s| parse_date(s, today);
+        assert_eq!(date("2020-12-31"), Some(NaiveDate::from_ymd(2020, 12, 31)));
+        assert_eq!(date("02-28"), Some(NaiveDate::from_ymd(2021, 2, 28)));
+        assert_eq!(date("2-3"), Some(NaiveDate::from_ymd(2021, 2, 3)));
+        assert_eq!(date("02-29"), None);
+        assert_eq!(date("2021-03"), None);
+        assert_eq!(date("-10"), None);
+        assert_eq!(date("10"), None);
+        assert_eq!(date("1-2-3-4"), None);
+        assert_eq!(date("03-05 "), None);
+    }
+
+    #[test]
+    fn test_error_offset() {
+        let offset = |input: &str| command_split(input).unwrap_err().offset;
+        assert_eq!(offset("foo\"#bar"), 3);
+        assert_eq!(offset("10 ali food 'din ner"), 12);
+        assert_eq!(offset("10 '食物' \"晚饭"), 8);
+        assert_eq!(offset("   foo \nbar"), 7);
+        assert_eq!(offset("foo \"b\nar\""), 6);
+        assert_eq!(offset(r#"'a' "b\"#), 4);
+
+        let annotate = |input: &str| command_split(input).unwrap_err().annotate(input);
+        assert_eq!(
+            annotate("10 ali food 'din"),
+            "10 ali food 'din\n            ^"
+        );
+        assert_eq!(annotate("10\tali 'food"), "10\tali 'food\n  \t    ^");
+        assert_eq!(annotate("foo 'a\nb'"), "foo 'a\n      ^");
+    }
+
+    #[test]
+    fn test_components() {
+        assert_eq!(last_component("Expenses:Food:Snack", ":"), "Snack");
+        assert_eq!(last_component("Expenses", ":"), "Expenses");
+        assert_eq!(last_component("Expenses/Food/Snack", "/"), "Snack");
+        assert_eq!(last_component("Expenses/Food:Snack", "/"), "Food:Snack");
+        assert_eq!(last_component("Expenses::Food", "::"), "Food");
+        assert!(has_root("Expenses:Food", "Expenses", ":"));
+        assert!(has_root("Expenses/Food", "Expenses", "/"));
+        assert!(!has_root("Expenses/Food", "Expenses", ":"));
+        assert!(!has_root("ExpensesX:Food", "Expenses", ":"));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let eval = |s: &str| evaluate(s).map(|d| d.to_string());
+        assert_eq!(eval("12*3+5").unwrap(), "41");
+        assert_eq!(eval("5 + 12 * 3").unwrap(), "41");
+        assert_eq!(eval("(5 + 12) * 3").unwrap(), "51");
+        assert_eq!(eval("10 - 4 - 3").unwrap(), "3");
+        assert_eq!(eval("24 / 4 / 2").unwrap(), "3");
+        assert_eq!(eval("-(2 + 3) * -2").unwrap(), "10");
+        assert_eq!(eval("1.5 + 2.25").unwrap(), "3.75");
+        assert_eq!(eval("10 / 3").unwrap(), "3.33333333");
+        assert_eq!(eval("2 / 3").unwrap(), "0.66666667");
+        assert_eq!(eval("100 / 3 * 3").unwrap(), "100");
+        assert_eq!(eval("1 / 0").unwrap_err().to_string(), "Division by zero");
+        assert_eq!(
+            eval("(1 + 2").unwrap_err().to_string(),
+            "Unmatched parenthesis"
+        );
+        assert_eq!(
+            eval("1 + 2)").unwrap_err().to_string(),
+            "Unexpected character ')'"
+        );
+        assert_eq!(
+            eval("2 ^ 3").unwrap_err().to_string(),
+            "Unexpected character '^'"
+        );
+        assert_eq!(
+            eval("").unwrap_err().to_string(),
+            "Unexpected end of expression"
+        );
+        assert!(eval("1..2").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_depth() {
+        let eval = |s: &str| evaluate(s).map(|d| d.to_string());
+        let nested = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(eval(&nested(CALC_MAX_DEPTH - 1)).unwrap(), "1");
+        assert_eq!(
+            eval(&nested(CALC_MAX_DEPTH)).unwrap_err().to_string(),
+            "Expression nested too deeply"
+        );
+        assert_eq!(eval(&format!("{}1", "-".repeat(10))).unwrap(), "1");
+        assert_eq!(
+            eval(&format!("{}1", "-".repeat(100_000)))
+                .unwrap_err()
+                .to_string(),
+            "Expression nested too deeply"
+        );
+    }
 }